@@ -0,0 +1,553 @@
+//! Async (tokio) transports and MSP client, enabled by the `tokio` feature.
+//!
+//! Frames are decoded with the same [`MspParser`] as the blocking
+//! [`msp::reader`]. TCP and UDP use tokio sockets directly; serial I/O runs
+//! the blocking [`serial::SerialDevice`] on dedicated threads.
+//!
+//! ```no_run
+//! # async fn f() -> Result<(), Box<dyn std::error::Error>> {
+//! use std::time::Duration;
+//! use msptest::asyncio;
+//! use msptest::messages::Name;
+//!
+//! let mut client = asyncio::open("tcp://localhost:5760").await?;
+//! let mut debug = client.unsolicited().unwrap();
+//! let name: Name = client.request_payload(&[], Duration::from_millis(500)).await?;
+//! println!("{}", name.name);
+//! while let Some(msg) = debug.next().await {
+//!     println!("{:?}", msg);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::client::ClientError;
+use crate::messages::Payload;
+use crate::msp::{self, MSPMsg, MSPRes, MspParser};
+use crate::serial_options::SerialOptions;
+use crate::transport::Endpoint;
+use crate::{net, parse_dev, serial};
+use std::io;
+use std::io::{Read, Write};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{mpsc, oneshot};
+
+/// Connects to `host:port` over TCP, with Nagle disabled.
+pub async fn tcp_connect(host: &str, port: u16) -> io::Result<TcpStream> {
+    let conn = TcpStream::connect((host, port)).await?;
+    _ = conn.set_nodelay(true);
+    Ok(conn)
+}
+
+/// Connected UDP socket usable as an `AsyncRead` / `AsyncWrite` stream.
+/// Clones share the socket, but not buffered input.
+///
+/// Each write is sent as one datagram; a datagram larger than the read
+/// buffer is returned over successive reads rather than truncated.
+#[derive(Debug)]
+pub struct UdpStream {
+    socket: Arc<UdpSocket>,
+    dgram: Vec<u8>,
+    len: usize,
+    pos: usize,
+}
+
+impl Clone for UdpStream {
+    fn clone(&self) -> Self {
+        UdpStream {
+            socket: self.socket.clone(),
+            dgram: Vec::new(),
+            len: 0,
+            pos: 0,
+        }
+    }
+}
+
+/// Connects a UDP socket to `host:port`, bound to the local port `bind` if
+/// given or else to any port.
+pub async fn udp_connect(host: &str, port: u16, bind: Option<u16>) -> io::Result<UdpStream> {
+    let remote = tokio::net::lookup_host((host, port))
+        .await?
+        .next()
+        .ok_or_else(net::no_address)?;
+    let socket = UdpSocket::bind(net::bind_addr(&remote, bind.unwrap_or(0))).await?;
+    socket.connect(remote).await?;
+    Ok(UdpStream {
+        socket: Arc::new(socket),
+        dgram: Vec::new(),
+        len: 0,
+        pos: 0,
+    })
+}
+
+/// Listens on `host:port` and returns the first TCP connection.
+pub async fn tcp_accept(host: &str, port: u16) -> io::Result<TcpStream> {
+    let (conn, _) = TcpListener::bind((host, port)).await?.accept().await?;
+    _ = conn.set_nodelay(true);
+    Ok(conn)
+}
+
+/// Binds a UDP socket to `host:port`, waits for a datagram and returns the
+/// socket connected to its sender. The datagram is the first data read from
+/// the returned stream (not from its clones).
+pub async fn udp_accept(host: &str, port: u16) -> io::Result<UdpStream> {
+    let socket = UdpSocket::bind((host, port)).await?;
+    let mut dgram = vec![0; net::MAX_DATAGRAM];
+    let (len, peer) = socket.recv_from(&mut dgram).await?;
+    socket.connect(peer).await?;
+    Ok(UdpStream {
+        socket: Arc::new(socket),
+        dgram,
+        len,
+        pos: 0,
+    })
+}
+
+impl AsyncRead for UdpStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        while this.pos == this.len {
+            if this.dgram.is_empty() {
+                this.dgram = vec![0; net::MAX_DATAGRAM];
+            }
+            let mut rb = ReadBuf::new(&mut this.dgram);
+            match this.socket.poll_recv(cx, &mut rb) {
+                Poll::Ready(Ok(())) => {
+                    this.len = rb.filled().len();
+                    this.pos = 0;
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let n = buf.remaining().min(this.len - this.pos);
+        buf.put_slice(&this.dgram[this.pos..this.pos + n]);
+        this.pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for UdpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.socket.poll_send(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Reader half of an async serial device.
+#[derive(Debug)]
+pub struct SerialReader {
+    rx: mpsc::Receiver<Vec<u8>>,
+    buf: Vec<u8>,
+}
+
+/// Writer half of an async serial device. Writes are queued to a writer
+/// thread and complete immediately.
+#[derive(Debug)]
+pub struct SerialWriter {
+    tx: Option<std::sync::mpsc::Sender<Vec<u8>>>,
+}
+
+/// Opens a serial device, returning async reader and writer halves.
+pub fn serial_open(
+    name: &str,
+    baud: u32,
+    opts: &SerialOptions,
+) -> io::Result<(SerialReader, SerialWriter)> {
+    let mut sd = serial::SerialDevice::new();
+    sd.open_with(name, baud as isize, opts)?;
+    sd.clear();
+
+    let mut rd = sd.clone();
+    let (rtx, rrx) = mpsc::channel(16);
+    thread::spawn(move || {
+        let mut inp = [0u8; 256];
+        while let Ok(n @ 1..) = rd.read(&mut inp) {
+            if rtx.blocking_send(inp[..n].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+
+    let (wtx, wrx) = std::sync::mpsc::channel::<Vec<u8>>();
+    thread::spawn(move || {
+        for buf in wrx {
+            if sd.write_all(&buf).is_err() {
+                break;
+            }
+        }
+        // Closing the device when the writer is dropped stops the reader.
+        sd.close();
+    });
+
+    Ok((
+        SerialReader {
+            rx: rrx,
+            buf: Vec::new(),
+        },
+        SerialWriter { tx: Some(wtx) },
+    ))
+}
+
+impl AsyncRead for SerialReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.buf.is_empty() {
+            match self.rx.poll_recv(cx) {
+                Poll::Ready(Some(data)) => self.buf = data,
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let n = self.buf.len().min(buf.remaining());
+        buf.put_slice(&self.buf[..n]);
+        self.buf.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for SerialWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.tx.as_ref().map(|tx| tx.send(buf.to_vec())) {
+            Some(Ok(())) => Poll::Ready(Ok(buf.len())),
+            _ => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.tx = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Opens a device specification (as [`parse_dev::parse_uri_dev`]) and
+/// returns a client on it.
+pub async fn open(spec: &str) -> io::Result<AsyncMspClient> {
+    match parse_dev::parse_uri_dev(spec)? {
+        Endpoint::Serial { name, baud, opts } => {
+            let (r, w) = serial_open(&name, baud, &opts)?;
+            Ok(AsyncMspClient::new(r, w))
+        }
+        Endpoint::Tcp { host, port } => {
+            let (r, w) = tcp_connect(&host, port).await?.into_split();
+            Ok(AsyncMspClient::new(r, w))
+        }
+        Endpoint::Udp { host, port, bind } => {
+            let u = udp_connect(&host, port, bind).await?;
+            // The reader keeps any buffered datagram.
+            let w = u.clone();
+            Ok(AsyncMspClient::new(u, w))
+        }
+        Endpoint::TcpListen { host, port } => {
+            let (r, w) = tcp_accept(&host, port).await?.into_split();
+            Ok(AsyncMspClient::new(r, w))
+        }
+        Endpoint::UdpListen { host, port } => {
+            let u = udp_accept(&host, port).await?;
+            // The reader keeps any buffered datagram.
+            let w = u.clone();
+            Ok(AsyncMspClient::new(u, w))
+        }
+        Endpoint::Fault { .. } => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "fault injection is not supported for async links",
+        )),
+        Endpoint::Replay { .. } => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "replay is not supported for async links",
+        )),
+    }
+}
+
+/// Frames buffered for the [`Unsolicited`] stream; further frames are
+/// dropped until it is read.
+pub const UNSOLICITED_CAPACITY: usize = 64;
+
+// Outstanding request, shared with the reader task.
+#[derive(Default)]
+struct Pending {
+    closed: bool,
+    waiter: Option<(u16, oneshot::Sender<MSPMsg>)>,
+}
+
+/// Async MSP client with per-request timeouts and retries.
+///
+/// Replies are matched to the outstanding request by command id; all other
+/// frames are delivered to the [`Unsolicited`] stream.
+pub struct AsyncMspClient {
+    writer: Box<dyn AsyncWrite + Unpin + Send>,
+    pending: Arc<Mutex<Pending>>,
+    unsolicited: Option<Unsolicited>,
+    reader: tokio::task::JoinHandle<()>,
+    vers: u8,
+    retries: u32,
+}
+
+impl AsyncMspClient {
+    /// Creates a client over the `reader` / `writer` halves of a transport.
+    /// Must be called from within a tokio runtime (the reader is spawned as
+    /// a task).
+    pub fn new<R, W>(mut reader: R, writer: W) -> Self
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let pending = Arc::new(Mutex::new(Pending::default()));
+        let (utx, urx) = mpsc::channel(UNSOLICITED_CAPACITY);
+        let p = pending.clone();
+        let reader = tokio::spawn(async move {
+            let mut parser = MspParser::new();
+            let mut inp = [0u8; 256];
+            while let Ok(n @ 1..) = reader.read(&mut inp).await {
+                for msg in parser.feed(&inp[..n]) {
+                    if !matches!(msg.ok, MSPRes::Ok | MSPRes::Error) {
+                        continue;
+                    }
+                    let mut p = p.lock().unwrap();
+                    match p.waiter.take() {
+                        Some((cmd, tx)) if cmd == msg.cmd => _ = tx.send(msg),
+                        other => {
+                            p.waiter = other;
+                            _ = utx.try_send(msg);
+                        }
+                    }
+                }
+            }
+            // Dropping any waiter reports the disconnect.
+            let mut p = p.lock().unwrap();
+            p.closed = true;
+            p.waiter = None;
+        });
+        AsyncMspClient {
+            writer: Box::new(writer),
+            pending,
+            unsolicited: Some(Unsolicited { rx: urx }),
+            reader,
+            vers: 2,
+            retries: 2,
+        }
+    }
+
+    /// Sets the MSP version used for requests (default 2). Commands above
+    /// 255 are always sent as MSPv2.
+    pub fn set_version(&mut self, vers: u8) {
+        self.vers = vers;
+    }
+
+    /// Sets the number of times a timed-out request is resent (default 2).
+    pub fn set_retries(&mut self, retries: u32) {
+        self.retries = retries;
+    }
+
+    /// Takes the stream of frames that do not answer a request. Up to
+    /// [`UNSOLICITED_CAPACITY`] frames are buffered until it is taken (or
+    /// while it is not read); returns `None` if already taken.
+    pub fn unsolicited(&mut self) -> Option<Unsolicited> {
+        self.unsolicited.take()
+    }
+
+    /// Sends a request without waiting for a reply.
+    pub async fn send(&mut self, cmd: u16, payload: &[u8]) -> Result<(), ClientError> {
+        let buf = if self.vers == 1 && cmd < 256 {
+            msp::encode_msp(cmd, payload)?
+        } else {
+            msp::encode_msp2(cmd, payload)?
+        };
+        self.writer.write_all(&buf).await?;
+        Ok(())
+    }
+
+    /// Sends `cmd` and waits up to `timeout` for the reply, resending up to
+    /// the configured number of retries.
+    pub async fn request(
+        &mut self,
+        cmd: u16,
+        payload: &[u8],
+        timeout: Duration,
+    ) -> Result<MSPMsg, ClientError> {
+        for _ in 0..=self.retries {
+            let (tx, rx) = oneshot::channel();
+            {
+                let mut p = self.pending.lock().unwrap();
+                if p.closed {
+                    return Err(ClientError::Disconnected);
+                }
+                p.waiter = Some((cmd, tx));
+            }
+            self.send(cmd, payload).await?;
+            match tokio::time::timeout(timeout, rx).await {
+                Ok(Ok(msg)) => {
+                    return match msg.ok {
+                        MSPRes::Error => Err(ClientError::Unsupported(cmd)),
+                        _ => Ok(msg),
+                    }
+                }
+                Ok(Err(_)) => return Err(ClientError::Disconnected),
+                Err(_) => (),
+            }
+        }
+        self.pending.lock().unwrap().waiter = None;
+        Err(ClientError::Timeout(cmd))
+    }
+
+    /// Requests and decodes the payload type `T`.
+    pub async fn request_payload<T: Payload>(
+        &mut self,
+        payload: &[u8],
+        timeout: Duration,
+    ) -> Result<T, ClientError> {
+        let msg = self.request(T::CMD.id(), payload, timeout).await?;
+        Ok(T::decode(&msg.data)?)
+    }
+}
+
+impl Drop for AsyncMspClient {
+    /// Stops the reader task.
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// Stream of frames that did not answer a request (e.g. `MSG_DEBUGMSG`).
+pub struct Unsolicited {
+    rx: mpsc::Receiver<MSPMsg>,
+}
+
+impl Unsolicited {
+    /// Receives the next frame; `None` once the transport has closed.
+    pub async fn next(&mut self) -> Option<MSPMsg> {
+        self.rx.recv().await
+    }
+}
+
+impl futures_core::Stream for Unsolicited {
+    type Item = MSPMsg;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<MSPMsg>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{Persona, Simulator};
+
+    const TIMEOUT: Duration = Duration::from_millis(500);
+
+    // A fake FC on a loopback TCP connection, answering requests from the
+    // simulator except for those `ignore` returns true for.
+    async fn client(ignore: fn(u16) -> bool) -> AsyncMspClient {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let conn = tcp_connect("127.0.0.1", listener.local_addr().unwrap().port())
+            .await
+            .unwrap();
+        let (mut fc, _) = listener.accept().await.unwrap();
+        tokio::spawn(async move {
+            let sim = Simulator::new(Persona::Inav19);
+            let mut parser = MspParser::new();
+            let mut inp = [0u8; 256];
+            while let Ok(n @ 1..) = fc.read(&mut inp).await {
+                let reqs: Vec<_> = parser.feed(&inp[..n]).collect();
+                for req in reqs.iter().filter(|r| !ignore(r.cmd)) {
+                    // Preceded by an unsolicited frame.
+                    let debug = msp::encode_response(1, msp::MSG_DEBUGMSG, b"x\0", false);
+                    _ = fc.write_all(&debug.unwrap()).await;
+                    _ = fc.write_all(&sim.respond(req).unwrap()).await;
+                }
+            }
+        });
+        let (r, w) = conn.into_split();
+        AsyncMspClient::new(r, w)
+    }
+
+    #[tokio::test]
+    async fn request_round_trip() {
+        let mut c = client(|_| false).await;
+        let mut debug = c.unsolicited().unwrap();
+        let msg = c.request(msp::MSG_IDENT, &[], TIMEOUT).await.unwrap();
+        assert!(msg.cmd == msp::MSG_IDENT && matches!(msg.ok, MSPRes::Ok));
+        let msg = tokio::time::timeout(TIMEOUT, debug.next()).await.unwrap();
+        assert_eq!(msg.map(|m| m.cmd), Some(msp::MSG_DEBUGMSG));
+        assert!(matches!(
+            c.request(msp::MSG_MISC2, &[], TIMEOUT).await,
+            Err(ClientError::Unsupported(msp::MSG_MISC2))
+        ));
+    }
+
+    #[tokio::test]
+    async fn request_timeout() {
+        let mut c = client(|cmd| cmd == msp::MSG_NAME).await;
+        c.set_retries(1);
+        let st = tokio::time::Instant::now();
+        assert!(matches!(
+            c.request(msp::MSG_NAME, &[], Duration::from_millis(50))
+                .await,
+            Err(ClientError::Timeout(msp::MSG_NAME))
+        ));
+        assert!(st.elapsed() >= Duration::from_millis(100));
+        // Still usable afterwards.
+        c.request(msp::MSG_IDENT, &[], TIMEOUT).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn udp_accept_keeps_first_datagram() {
+        // Reserve a free port for the listener.
+        let port = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let accept = tokio::spawn(udp_accept("127.0.0.1", port));
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let req = msp::encode_msp(msp::MSG_IDENT, &[]).unwrap();
+        // Resend until the listener is bound.
+        let mut u = loop {
+            peer.send_to(&req, ("127.0.0.1", port)).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            if accept.is_finished() {
+                break accept.await.unwrap().unwrap();
+            }
+        };
+        let mut buf = [0u8; 64];
+        let n = u.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], &req[..]);
+
+        let mut w = u.clone();
+        w.write_all(b"reply").await.unwrap();
+        let n = peer.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"reply");
+    }
+}
@@ -0,0 +1,235 @@
+// Transparent bridge between two links, e.g. a serial FC and a TCP
+// listener as a ser2net replacement. Bytes are forwarded unchanged in both
+// directions; the MSP parser runs on each stream to report frame counts,
+// CRC errors and throughput, i.e. whether the link is healthy.
+
+use crossbeam_channel::{select, tick, unbounded, Sender};
+use msptest::msp::{MSPRes, MspParser};
+use msptest::parse_dev;
+use msptest::reconnect::Backoff;
+use msptest::transport::{Endpoint, Transport};
+use std::io;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Interval between statistics lines.
+const REPORT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Counts {
+    bytes: u64,
+    frames: u64,
+    crc: u64,
+}
+
+// Counts for each direction: [0] first link to second, [1] the reverse.
+type Stats = Arc<Mutex<[Counts; 2]>>;
+
+fn forward(
+    mut rd: Box<dyn Read + Send>,
+    mut wr: Box<dyn Write + Send>,
+    dir: usize,
+    stats: Stats,
+    done: Sender<usize>,
+) {
+    let mut parser = MspParser::new();
+    let mut inp = [0u8; 1024];
+    loop {
+        let n = match rd.read(&mut inp) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(_) => break,
+        };
+        if wr.write_all(&inp[..n]).is_err() {
+            break;
+        }
+        let mut st = stats.lock().unwrap();
+        let c = &mut st[dir];
+        c.bytes += n as u64;
+        for msg in parser.feed(&inp[..n]) {
+            match msg.ok {
+                MSPRes::Crc => c.crc += 1,
+                _ => c.frames += 1,
+            }
+        }
+    }
+    _ = done.send(dir);
+}
+
+fn endpoint(dev: &str, autodev: &str) -> io::Result<Endpoint> {
+    match dev {
+        "auto" => Ok(Endpoint::Serial {
+            name: autodev.to_string(),
+            baud: 115200,
+            opts: Default::default(),
+        }),
+        _ => Ok(parse_dev::parse_uri_dev(dev)?),
+    }
+}
+
+fn report(et: f64, now: &[Counts; 2], last: &[Counts; 2], secs: f64) {
+    let dir = |c: &Counts, l: &Counts| {
+        format!(
+            "{} frames, {} CRC, {:.0} B/s",
+            c.frames,
+            c.crc,
+            (c.bytes - l.bytes) as f64 / secs
+        )
+    };
+    println!(
+        "{:8.1}s  > {}  < {}",
+        et,
+        dir(&now[0], &last[0]),
+        dir(&now[1], &last[1])
+    );
+}
+
+/// Runs the bridge between the two links in `args`; `auto` is the
+/// auto-discovered serial device `autodev`. When either side closes, both
+/// are closed and reopened (e.g. to accept the next TCP client).
+pub fn run(args: &[String], autodev: &str) -> io::Result<()> {
+    if args.len() != 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "bridge needs two devices / URIs",
+        ));
+    }
+    let eps = [endpoint(&args[0], autodev)?, endpoint(&args[1], autodev)?];
+    let mut tps: Vec<Box<dyn Transport>> = eps.iter().map(Endpoint::transport).collect();
+    let stats: Stats = Default::default();
+    let start = Instant::now();
+    let mut backoff = Backoff::default();
+
+    'a: loop {
+        for (ep, tp) in eps.iter().zip(tps.iter_mut()) {
+            if matches!(ep, Endpoint::TcpListen { .. } | Endpoint::UdpListen { .. }) {
+                println!("{} waiting for peer", ep);
+            }
+            if let Err(e) = tp.open() {
+                let delay = backoff.next_delay();
+                eprintln!("{}: {} (attempt {})", ep, e, backoff.attempt());
+                tps.iter_mut().for_each(|tp| tp.close());
+                thread::sleep(delay);
+                continue 'a;
+            }
+        }
+        let halves: io::Result<Vec<_>> = (0..2)
+            .map(|dir| Ok((tps[dir].reader()?, tps[1 - dir].writer()?)))
+            .collect();
+        let halves = match halves {
+            Ok(h) => h,
+            Err(e) => {
+                let delay = backoff.next_delay();
+                eprintln!(
+                    "{} <-> {}: {} (attempt {})",
+                    eps[0],
+                    eps[1],
+                    e,
+                    backoff.attempt()
+                );
+                tps.iter_mut().for_each(|tp| tp.close());
+                thread::sleep(delay);
+                continue 'a;
+            }
+        };
+        backoff.reset();
+        println!(
+            "Bridging {} <-> {}",
+            tps[0].description(),
+            tps[1].description()
+        );
+
+        let (dtx, drx) = unbounded();
+        let mut thrs = Vec::new();
+        for (dir, (rd, wr)) in halves.into_iter().enumerate() {
+            let (stats, dtx) = (stats.clone(), dtx.clone());
+            thrs.push(thread::spawn(move || forward(rd, wr, dir, stats, dtx)));
+        }
+
+        let ticks = tick(REPORT);
+        let mut last = *stats.lock().unwrap();
+        let mut lastt = Instant::now();
+        loop {
+            select! {
+                recv(drx) -> _ => break,
+                recv(ticks) -> _ => {
+                    let now = *stats.lock().unwrap();
+                    report(start.elapsed().as_secs_f64(), &now, &last, lastt.elapsed().as_secs_f64());
+                    last = now;
+                    lastt = Instant::now();
+                }
+            }
+        }
+        tps.iter_mut().for_each(|tp| tp.close());
+        for t in thrs {
+            _ = t.join();
+        }
+        let now = *stats.lock().unwrap();
+        report(
+            start.elapsed().as_secs_f64(),
+            &now,
+            &last,
+            lastt.elapsed().as_secs_f64(),
+        );
+        println!("Link closed");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use msptest::msp;
+
+    // Returns the data a few bytes per read, so frames span reads.
+    struct Chunked(io::Cursor<Vec<u8>>);
+
+    impl Read for Chunked {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(7);
+            self.0.read(&mut buf[..n])
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn forwards_bytes_and_counts_frames() {
+        let mut data = b"noise".to_vec();
+        data.extend(msp::encode_msp(msp::MSG_IDENT, &[]).unwrap());
+        data.extend(msp::encode_msp2(msp::MSG_NAME, &[0x41; 300]).unwrap());
+        let mut bad = msp::encode_msp(msp::MSG_STATUS_EX, &[1, 2, 3]).unwrap();
+        *bad.last_mut().unwrap() ^= 0xff;
+        data.extend(bad);
+        data.extend(msp::encode_msp(msp::MSG_NAME, &[0x55; 600]).unwrap());
+
+        let out = Shared::default();
+        let stats: Stats = Default::default();
+        let (dtx, drx) = unbounded();
+        let rd = Box::new(Chunked(io::Cursor::new(data.clone())));
+        forward(rd, Box::new(out.clone()), 1, stats.clone(), dtx);
+
+        assert_eq!(*out.0.lock().unwrap(), data);
+        assert_eq!(drx.try_recv(), Ok(1));
+        let st = stats.lock().unwrap();
+        assert_eq!(
+            (st[1].bytes, st[1].frames, st[1].crc),
+            (data.len() as u64, 3, 1)
+        );
+        assert_eq!(st[0].bytes, 0);
+    }
+}
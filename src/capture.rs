@@ -0,0 +1,304 @@
+//! Raw link capture, recording every byte read from and written to a link
+//! (including noise and partial frames) for later analysis.
+//!
+//! A capture file starts with the 8 byte magic [`MAGIC`], followed by
+//! records of:
+//!
+//! | Field | Size | |
+//! |-------|------|-|
+//! | type  | 1    | `S` session start, `<` written to the FC, `>` read from the FC |
+//! | time  | 8    | microseconds since the Unix epoch, little endian |
+//! | len   | 2    | data length, little endian |
+//! | data  | len  | |
+//!
+//! The data of a session record, written at each connection, is the
+//! msptest version, the link description and the baud rate (0 for network
+//! links), separated by NULs.
+//!
+//! A [`Capture`] can instead write pcapng directly (see [`crate::pcap`]),
+//! which keeps only the MSP frames.
+
+use crate::pcap::PcapExporter;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Capture file magic: `MSPCAP`, format version 1 and a reserved byte.
+pub const MAGIC: [u8; 8] = *b"MSPCAP\x01\x00";
+
+/// Direction of captured data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Written to the FC.
+    ToFc,
+    /// Read from the FC.
+    FromFc,
+}
+
+impl Direction {
+    /// The record type, as the MSP direction character (`<` or `>`).
+    pub fn as_char(self) -> char {
+        match self {
+            Direction::ToFc => '<',
+            Direction::FromFc => '>',
+        }
+    }
+}
+
+/// A captured event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// Start of a connection.
+    Session {
+        version: String,
+        endpoint: String,
+        baud: u32,
+    },
+    /// Bytes transferred.
+    Data { dir: Direction, data: Vec<u8> },
+}
+
+/// A capture file record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Microseconds since the Unix epoch.
+    pub time_us: u64,
+    pub event: Event,
+}
+
+fn now_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_micros() as u64)
+}
+
+/// Capture file writer, shared by clones (e.g. a link's reader and writer
+/// threads). Each record is written with a single unbuffered write, so the
+/// file is complete up to the last record if msptest exits abruptly.
+///
+/// ```
+/// use std::io::{Read, Write};
+/// use msptest::capture::{self, Capture, Direction, Event};
+///
+/// let path = std::env::temp_dir().join(format!("msptest-doc-{}.cap", std::process::id()));
+/// let cap = Capture::create(&path).unwrap();
+/// cap.session("tcp://localhost:5760", 0).unwrap();
+/// cap.writer(Vec::new()).write_all(b"$M<\x00\x64\x64").unwrap();
+/// let mut buf = [0u8; 16];
+/// cap.reader(&b"$M>junk"[..]).read(&mut buf).unwrap();
+///
+/// let recs: Vec<_> = capture::open(&path).unwrap().map(Result::unwrap).collect();
+/// assert!(matches!(recs[0].event, Event::Session { ref endpoint, .. } if endpoint == "tcp://localhost:5760"));
+/// assert_eq!(recs[1].event, Event::Data { dir: Direction::ToFc, data: b"$M<\x00\x64\x64".to_vec() });
+/// assert_eq!(recs[2].event, Event::Data { dir: Direction::FromFc, data: b"$M>junk".to_vec() });
+/// # std::fs::remove_file(&path).unwrap();
+/// ```
+#[derive(Clone)]
+pub struct Capture {
+    sink: Arc<Mutex<Sink>>,
+}
+
+enum Sink {
+    Raw(Box<dyn Write + Send>),
+    Pcap(PcapExporter<Box<dyn Write + Send>>),
+}
+
+impl Capture {
+    /// Writes a capture to `w`, starting with the magic.
+    pub fn new<W: Write + Send + 'static>(mut w: W) -> io::Result<Self> {
+        w.write_all(&MAGIC)?;
+        Ok(Self::with_sink(Sink::Raw(Box::new(w))))
+    }
+
+    /// Creates (or truncates) the capture file `path`.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Self::new(File::create(path)?)
+    }
+
+    /// Writes the MSP frames transferred to `w` as pcapng, rather than a
+    /// raw capture.
+    pub fn new_pcap<W: Write + Send + 'static>(w: W) -> io::Result<Self> {
+        let w: Box<dyn Write + Send> = Box::new(w);
+        Ok(Self::with_sink(Sink::Pcap(PcapExporter::new(w)?)))
+    }
+
+    /// Creates (or truncates) the pcapng file `path`.
+    pub fn create_pcap(path: &Path) -> io::Result<Self> {
+        Self::new_pcap(File::create(path)?)
+    }
+
+    fn with_sink(sink: Sink) -> Self {
+        Self {
+            sink: Arc::new(Mutex::new(sink)),
+        }
+    }
+
+    fn write(w: &mut dyn Write, typ: u8, data: &[u8]) -> io::Result<()> {
+        let mut v = Vec::with_capacity(data.len() + 11);
+        v.push(typ);
+        v.extend_from_slice(&now_us().to_le_bytes());
+        v.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        v.extend_from_slice(data);
+        w.write_all(&v)
+    }
+
+    /// Records the start of a connection to `endpoint`.
+    pub fn session(&self, endpoint: &str, baud: u32) -> io::Result<()> {
+        match *self.sink.lock().unwrap() {
+            Sink::Raw(ref mut w) => {
+                let info = format!("{}\0{}\0{}", env!("CARGO_PKG_VERSION"), endpoint, baud);
+                Self::write(w, b'S', info.as_bytes())
+            }
+            Sink::Pcap(ref mut x) => x.session(endpoint, baud),
+        }
+    }
+
+    /// Records data transferred in direction `dir`.
+    pub fn record(&self, dir: Direction, data: &[u8]) -> io::Result<()> {
+        match *self.sink.lock().unwrap() {
+            Sink::Raw(ref mut w) => {
+                // Records are limited to 64k; larger transfers are split.
+                for chunk in data.chunks(u16::MAX as usize) {
+                    Self::write(w, dir.as_char() as u8, chunk)?;
+                }
+                Ok(())
+            }
+            Sink::Pcap(ref mut x) => x.data(now_us(), dir, data),
+        }
+    }
+
+    /// Wraps a link reader, recording the data read.
+    pub fn reader<R: Read>(&self, inner: R) -> CaptureReader<R> {
+        CaptureReader {
+            inner,
+            cap: self.clone(),
+        }
+    }
+
+    /// Wraps a link writer, recording the data written.
+    pub fn writer<W: Write>(&self, inner: W) -> CaptureWriter<W> {
+        CaptureWriter {
+            inner,
+            cap: self.clone(),
+        }
+    }
+}
+
+/// Reader recording the data read from `inner` as [`Direction::FromFc`].
+/// Capture errors are ignored, so as not to disturb the link.
+pub struct CaptureReader<R> {
+    inner: R,
+    cap: Capture,
+}
+
+impl<R: Read> Read for CaptureReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            _ = self.cap.record(Direction::FromFc, &buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+/// Writer recording the data written to `inner` as [`Direction::ToFc`].
+/// The data is recorded before it is written, so that it precedes any
+/// reply in the capture; each write is written in full.
+pub struct CaptureWriter<W> {
+    inner: W,
+    cap: Capture,
+}
+
+impl<W: Write> Write for CaptureWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        _ = self.cap.record(Direction::ToFc, buf);
+        self.inner.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Iterator over the records of a capture; iteration ends after an error
+/// (e.g. a truncated record).
+pub struct CaptureFile<R> {
+    inner: R,
+    failed: bool,
+}
+
+fn bad_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+impl<R: Read> CaptureFile<R> {
+    /// Reads a capture from `inner`, checking the magic.
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut magic = [0u8; 8];
+        inner.read_exact(&mut magic)?;
+        if magic[..6] != MAGIC[..6] {
+            return Err(bad_data("not a msptest capture"));
+        }
+        if magic[6] != MAGIC[6] {
+            return Err(bad_data("unsupported capture format version"));
+        }
+        Ok(Self {
+            inner,
+            failed: false,
+        })
+    }
+
+    fn next_record(&mut self) -> io::Result<Option<Record>> {
+        let mut hdr = [0u8; 11];
+        match self.inner.read(&mut hdr[..1])? {
+            0 => return Ok(None),
+            _ => self.inner.read_exact(&mut hdr[1..])?,
+        }
+        let time_us = u64::from_le_bytes(hdr[1..9].try_into().unwrap());
+        let mut data = vec![0u8; u16::from_le_bytes([hdr[9], hdr[10]]) as usize];
+        self.inner.read_exact(&mut data)?;
+        let event = match hdr[0] {
+            b'<' => Event::Data {
+                dir: Direction::ToFc,
+                data,
+            },
+            b'>' => Event::Data {
+                dir: Direction::FromFc,
+                data,
+            },
+            b'S' => {
+                let info = String::from_utf8_lossy(&data);
+                let mut f = info.split('\0');
+                Event::Session {
+                    version: f.next().unwrap_or_default().to_string(),
+                    endpoint: f.next().unwrap_or_default().to_string(),
+                    baud: f.next().and_then(|b| b.parse().ok()).unwrap_or(0),
+                }
+            }
+            _ => return Err(bad_data("bad capture record type")),
+        };
+        Ok(Some(Record { time_us, event }))
+    }
+}
+
+impl<R: Read> Iterator for CaptureFile<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let res = self.next_record().transpose();
+        self.failed = matches!(res, Some(Err(_)));
+        res
+    }
+}
+
+/// Opens the capture file `path`.
+pub fn open(path: &Path) -> io::Result<CaptureFile<BufReader<File>>> {
+    CaptureFile::new(BufReader::new(File::open(path)?))
+}
@@ -0,0 +1,361 @@
+//! Request / response MSP client.
+//!
+//! [`MspClient`] owns the writer half of a transport and a reader thread on
+//! the reader half, and matches each reply to its request by command id.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use msptest::client::MspClient;
+//! use msptest::messages::ApiVersion;
+//! use msptest::parse_dev;
+//!
+//! let ep = parse_dev::parse_uri_dev("tcp://localhost:5760").unwrap();
+//! let mut client = MspClient::open(&ep).unwrap();
+//! let api: ApiVersion = client.request_payload(&[], Duration::from_millis(500)).unwrap();
+//! println!("API {}.{}", api.major, api.minor);
+//! ```
+
+use crate::messages::{DecodeError, Payload};
+use crate::msp::{self, EncodeError, MSPMsg, MSPRes, MspParser};
+use crate::transport::{Endpoint, Transport};
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender, TrySendError};
+use std::collections::VecDeque;
+use std::io;
+use std::io::{Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Error returned by [`MspClient`] requests.
+#[derive(Debug)]
+pub enum ClientError {
+    /// No reply to the command within the timeout (after any retries).
+    Timeout(u16),
+    /// The FC replied with an error frame (command not supported).
+    Unsupported(u16),
+    /// The reply payload could not be decoded.
+    Decode(DecodeError),
+    /// The request could not be encoded.
+    Encode(EncodeError),
+    /// Write failure.
+    Io(io::Error),
+    /// The reader has stopped (EOF or read error).
+    Disconnected,
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Timeout(cmd) => write!(f, "timeout waiting for reply to {}", cmd),
+            ClientError::Unsupported(cmd) => write!(f, "command {} not supported", cmd),
+            ClientError::Decode(e) => write!(f, "{}", e),
+            ClientError::Encode(e) => write!(f, "{}", e),
+            ClientError::Io(e) => write!(f, "{}", e),
+            ClientError::Disconnected => write!(f, "disconnected"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        ClientError::Io(e)
+    }
+}
+
+impl From<EncodeError> for ClientError {
+    fn from(e: EncodeError) -> Self {
+        ClientError::Encode(e)
+    }
+}
+
+impl From<DecodeError> for ClientError {
+    fn from(e: DecodeError) -> Self {
+        ClientError::Decode(e)
+    }
+}
+
+/// Unsolicited frames kept by [`MspClient`]; further frames are dropped
+/// until they are collected.
+pub const UNSOLICITED_CAPACITY: usize = 64;
+
+/// Blocking MSP client with per-request timeouts and retries.
+///
+/// Frames that do not answer the current request (e.g. `MSG_DEBUGMSG`, or
+/// late replies to a timed-out request) are queued and may be collected with
+/// [`MspClient::unsolicited`]; once [`UNSOLICITED_CAPACITY`] are queued,
+/// further frames are dropped, as for
+/// [`AsyncMspClient`](crate::asyncio::AsyncMspClient).
+///
+/// The reader thread runs until the transport reaches EOF or fails. For a
+/// client created with [`MspClient::open`], dropping the client closes the
+/// transport.
+pub struct MspClient {
+    transport: Option<Box<dyn Transport>>,
+    writer: Box<dyn Write + Send>,
+    rx: Receiver<MSPMsg>,
+    vers: u8,
+    retries: u32,
+    connected: bool,
+    pending: VecDeque<MSPMsg>,
+}
+
+impl MspClient {
+    /// Creates a client over the `reader` / `writer` halves of a transport.
+    pub fn new<R, W>(reader: R, writer: W) -> Self
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let (tx, rx) = bounded(UNSOLICITED_CAPACITY);
+        thread::spawn(move || read_frames(reader, tx));
+        MspClient {
+            transport: None,
+            writer: Box::new(writer),
+            rx,
+            vers: 2,
+            retries: 2,
+            connected: true,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Opens `ep` and creates a client on it.
+    pub fn open(ep: &Endpoint) -> io::Result<Self> {
+        let mut tp = ep.transport();
+        tp.open()?;
+        let mut client = Self::new(tp.reader()?, tp.writer()?);
+        client.transport = Some(tp);
+        Ok(client)
+    }
+
+    /// Sets the MSP version used for requests (default 2). Commands above
+    /// 255 are always sent as MSPv2.
+    pub fn set_version(&mut self, vers: u8) {
+        self.vers = vers;
+    }
+
+    /// Sets the number of times a timed-out request is resent (default 2).
+    pub fn set_retries(&mut self, retries: u32) {
+        self.retries = retries;
+    }
+
+    /// Sends a request without waiting for a reply.
+    pub fn send(&mut self, cmd: u16, payload: &[u8]) -> Result<(), ClientError> {
+        let buf = if self.vers == 1 && cmd < 256 {
+            msp::encode_msp(cmd, payload)?
+        } else {
+            msp::encode_msp2(cmd, payload)?
+        };
+        self.writer.write_all(&buf)?;
+        Ok(())
+    }
+
+    /// Sends `cmd` and waits up to `timeout` for the reply, resending up to
+    /// the configured number of retries.
+    pub fn request(
+        &mut self,
+        cmd: u16,
+        payload: &[u8],
+        timeout: Duration,
+    ) -> Result<MSPMsg, ClientError> {
+        self.drain();
+        for _ in 0..=self.retries {
+            self.send(cmd, payload)?;
+            if let Some(msg) = self.wait_for(cmd, timeout)? {
+                return match msg.ok {
+                    MSPRes::Error => Err(ClientError::Unsupported(cmd)),
+                    _ => Ok(msg),
+                };
+            }
+        }
+        Err(ClientError::Timeout(cmd))
+    }
+
+    /// Requests and decodes the payload type `T`.
+    pub fn request_payload<T: Payload>(
+        &mut self,
+        payload: &[u8],
+        timeout: Duration,
+    ) -> Result<T, ClientError> {
+        let msg = self.request(T::CMD.id(), payload, timeout)?;
+        Ok(T::decode(&msg.data)?)
+    }
+
+    /// Returns (and clears) the frames received that did not answer a request.
+    pub fn unsolicited(&mut self) -> Vec<MSPMsg> {
+        self.drain();
+        self.pending.drain(..).collect()
+    }
+
+    fn queue(&mut self, msg: MSPMsg) {
+        if self.pending.len() < UNSOLICITED_CAPACITY {
+            self.pending.push_back(msg);
+        }
+    }
+
+    // Waits for an `Ok` or `Error` reply to `cmd`; `None` on timeout.
+    fn wait_for(&mut self, cmd: u16, timeout: Duration) -> Result<Option<MSPMsg>, ClientError> {
+        if !self.connected {
+            return Err(ClientError::Disconnected);
+        }
+        let deadline = Instant::now() + timeout;
+        loop {
+            let msg = match self
+                .rx
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            {
+                Ok(msg) => msg,
+                Err(RecvTimeoutError::Timeout) => return Ok(None),
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.connected = false;
+                    return Err(ClientError::Disconnected);
+                }
+            };
+            match msg.ok {
+                MSPRes::Ok | MSPRes::Error if msg.cmd == cmd => return Ok(Some(msg)),
+                MSPRes::Ok | MSPRes::Error => self.queue(msg),
+                MSPRes::Fail => {
+                    self.connected = false;
+                    return Err(ClientError::Disconnected);
+                }
+                MSPRes::Crc | MSPRes::Request => (),
+            }
+        }
+    }
+
+    // Moves any already received frames to the unsolicited queue.
+    fn drain(&mut self) {
+        while let Ok(msg) = self.rx.try_recv() {
+            match msg.ok {
+                MSPRes::Ok | MSPRes::Error => self.queue(msg),
+                MSPRes::Fail => self.connected = false,
+                _ => (),
+            }
+        }
+    }
+}
+
+// As `msp::reader`, but only passes on replies (and the final `Fail`), and
+// drops them rather than blocking while the channel is full, i.e. while the
+// client is not reading.
+fn read_frames<R: Read>(mut rd: R, tx: Sender<MSPMsg>) {
+    let mut parser = MspParser::new();
+    let mut inp = [0u8; 256];
+    loop {
+        match rd.read(&mut inp) {
+            Ok(n @ 1..) => {
+                for msg in parser.feed(&inp[..n]) {
+                    if matches!(msg.ok, MSPRes::Ok | MSPRes::Error)
+                        && matches!(tx.try_send(msg), Err(TrySendError::Disconnected(_)))
+                    {
+                        return;
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => (),
+            _ => {
+                _ = tx.send(MSPMsg::default());
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for MspClient {
+    fn drop(&mut self) {
+        if let Some(mut tp) = self.transport.take() {
+            tp.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{Persona, Simulator};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const TIMEOUT: Duration = Duration::from_millis(500);
+
+    // A client on a loopback TCP connection to a fake FC, which calls `f`
+    // with each request received.
+    fn client<F>(mut f: F) -> MspClient
+    where
+        F: FnMut(&MSPMsg, &mut TcpStream) + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let conn = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut fc, _) = listener.accept().unwrap();
+        thread::spawn(move || {
+            let mut parser = MspParser::new();
+            let mut inp = [0u8; 256];
+            while let Ok(n @ 1..) = fc.read(&mut inp) {
+                for msg in parser.feed(&inp[..n]).collect::<Vec<_>>() {
+                    f(&msg, &mut fc);
+                }
+            }
+        });
+        MspClient::new(conn.try_clone().unwrap(), conn)
+    }
+
+    fn debug(n: u8) -> Vec<u8> {
+        msp::encode_response(1, msp::MSG_DEBUGMSG, &[n, 0], false).unwrap()
+    }
+
+    #[test]
+    fn reply_matches_request() {
+        let sim = Simulator::new(Persona::Inav19);
+        let mut c = client(move |req, fc| {
+            // Unrelated frames ahead of the reply.
+            let other = msp::encode_response(1, msp::MSG_ANALOG, &[0; 7], false).unwrap();
+            _ = fc.write_all(&other);
+            _ = fc.write_all(&debug(0));
+            _ = fc.write_all(&sim.respond(req).unwrap());
+        });
+        let msg = c.request(msp::MSG_IDENT, &[], TIMEOUT).unwrap();
+        assert!(msg.cmd == msp::MSG_IDENT && matches!(msg.ok, MSPRes::Ok));
+        let other: Vec<u16> = c.unsolicited().iter().map(|m| m.cmd).collect();
+        assert_eq!(other, [msp::MSG_ANALOG, msp::MSG_DEBUGMSG]);
+        assert!(matches!(
+            c.request(msp::MSG_MISC2, &[], TIMEOUT),
+            Err(ClientError::Unsupported(msp::MSG_MISC2))
+        ));
+    }
+
+    #[test]
+    fn timeout_after_retries() {
+        let sent = Arc::new(AtomicUsize::new(0));
+        let n = sent.clone();
+        let mut c = client(move |_, _| _ = n.fetch_add(1, Ordering::SeqCst));
+        c.set_retries(1);
+        let st = Instant::now();
+        assert!(matches!(
+            c.request(msp::MSG_NAME, &[], Duration::from_millis(50)),
+            Err(ClientError::Timeout(msp::MSG_NAME))
+        ));
+        assert!(st.elapsed() >= Duration::from_millis(100));
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(sent.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn unsolicited_is_capped() {
+        let sim = Simulator::new(Persona::Inav19);
+        let mut c = client(move |req, fc| match req.cmd {
+            msp::MSG_DEBUGMSG => (0..100).for_each(|i| _ = fc.write_all(&debug(i))),
+            _ => _ = fc.write_all(&sim.respond(req).unwrap()),
+        });
+        // Frames arriving while the client is idle.
+        c.send(msp::MSG_DEBUGMSG, &[]).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        c.request(msp::MSG_IDENT, &[], TIMEOUT).unwrap();
+        let msgs = c.unsolicited();
+        assert_eq!(msgs.len(), UNSOLICITED_CAPACITY);
+        // The first frames are kept.
+        assert!(msgs.iter().enumerate().all(|(i, m)| m.data[0] == i as u8));
+        assert!(c.unsolicited().is_empty());
+    }
+}
@@ -0,0 +1,302 @@
+//! Serial device and baud rate auto-detection.
+
+use crate::msp::{self, MSPRes, MspParser};
+use crate::serial::{SerialDevice, SerialOptions};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+// Common flight controller rates, most likely first.
+const STD_RATES: &[u32] = &[115200, 57600, 230400, 38400, 19200, 9600, 460800, 921600];
+
+// Further rates; non-standard ones are set via termios2 on Linux.
+#[cfg(target_os = "linux")]
+const EXTRA_RATES: &[u32] = &[250000, 400000, 500000, 1000000, 1500000, 2000000];
+
+/// USB vendor / product id pattern; a `None` product id matches any product.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsbId {
+    pub vid: u16,
+    pub pid: Option<u16>,
+}
+
+impl UsbId {
+    pub const fn new(vid: u16, pid: u16) -> Self {
+        Self {
+            vid,
+            pid: Some(pid),
+        }
+    }
+
+    fn matches(&self, vid: u16, pid: u16) -> bool {
+        self.vid == vid && self.pid.is_none_or(|p| p == pid)
+    }
+}
+
+/// Error returned for an invalid [`UsbId`] pattern.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsbIdError(String);
+
+impl fmt::Display for UsbIdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid USB id \"{}\" (expected VID:PID in hex, PID may be *)",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for UsbIdError {}
+
+impl FromStr for UsbId {
+    type Err = UsbIdError;
+
+    /// Parses `VID:PID` (hex, e.g. `1a86:7523`); the PID may be `*` or
+    /// omitted to match any product.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || UsbIdError(s.to_string());
+        let mut parts = s.trim().splitn(2, ':');
+        let vid = u16::from_str_radix(parts.next().unwrap_or_default(), 16).map_err(|_| err())?;
+        let pid = match parts.next() {
+            None | Some("*") => None,
+            Some(p) => Some(u16::from_str_radix(p, 16).map_err(|_| err())?),
+        };
+        Ok(UsbId { vid, pid })
+    }
+}
+
+impl fmt::Display for UsbId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.pid {
+            Some(p) => write!(f, "{:04x}:{:04x}", self.vid, p),
+            None => write!(f, "{:04x}:*", self.vid),
+        }
+    }
+}
+
+/// STM32 VCP, FTDI FT232.
+const FC_IDS: &[UsbId] = &[UsbId::new(0x0483, 0x5740), UsbId::new(0x0403, 0x6001)];
+
+/// CP210x bridge.
+const CP210X_ID: UsbId = UsbId::new(0x10c4, 0xea60);
+
+/// Decides which enumerated ports look like a flight controller.
+///
+/// STM32 VCP and FTDI adaptors are always recognised; further ids may be
+/// added with [`DeviceMatcher::add_id`]. On FreeBSD, `/dev/cuaU*` is also
+/// accepted. If a USB serial number is set, only the port with that serial
+/// number matches.
+#[derive(Debug, Clone)]
+pub struct DeviceMatcher {
+    ids: Vec<UsbId>,
+    serial: Option<String>,
+}
+
+impl Default for DeviceMatcher {
+    fn default() -> Self {
+        Self::new(true)
+    }
+}
+
+impl DeviceMatcher {
+    /// Creates a matcher for the built-in ids; CP210x bridges are included
+    /// if `testcvt` is set.
+    pub fn new(testcvt: bool) -> Self {
+        let mut ids = FC_IDS.to_vec();
+        if testcvt {
+            ids.push(CP210X_ID);
+        }
+        Self { ids, serial: None }
+    }
+
+    pub fn add_id(&mut self, id: UsbId) {
+        if !self.ids.contains(&id) {
+            self.ids.push(id);
+        }
+    }
+
+    /// Only matches the USB device with serial number `serial`.
+    pub fn set_serial(&mut self, serial: Option<String>) {
+        self.serial = serial;
+    }
+
+    fn matches(&self, p: &serialport::SerialPortInfo) -> bool {
+        match &p.port_type {
+            serialport::SerialPortType::UsbPort(pt) => match self.serial {
+                Some(ref sn) => pt
+                    .serial_number
+                    .as_ref()
+                    .is_some_and(|s| s.eq_ignore_ascii_case(sn)),
+                None => self.ids.iter().any(|id| id.matches(pt.vid, pt.pid)),
+            },
+            _ => {
+                self.serial.is_none()
+                    && std::env::consts::OS == "freebsd"
+                    && p.port_name.starts_with("/dev/cuaU")
+            }
+        }
+    }
+
+    /// Returns the first enumerated port that matches.
+    pub fn find(&self) -> Option<String> {
+        serialport::available_ports()
+            .ok()?
+            .into_iter()
+            .find(|p| self.matches(p))
+            .map(|p| p.port_name)
+    }
+
+    /// Enumerates all serial ports.
+    pub fn list(&self) -> io::Result<Vec<PortInfo>> {
+        let ports = serialport::available_ports().map_err(io::Error::other)?;
+        Ok(ports
+            .iter()
+            .map(|p| {
+                let mut pi = PortInfo {
+                    name: p.port_name.clone(),
+                    usb_id: None,
+                    manufacturer: None,
+                    product: None,
+                    serial_number: None,
+                    is_fc: self.matches(p),
+                };
+                if let serialport::SerialPortType::UsbPort(pt) = &p.port_type {
+                    pi.usb_id = Some(UsbId::new(pt.vid, pt.pid));
+                    pi.manufacturer = pt.manufacturer.clone();
+                    pi.product = pt.product.clone();
+                    pi.serial_number = pt.serial_number.clone();
+                }
+                pi
+            })
+            .collect())
+    }
+
+    /// Adds the ids listed in `path`, one `VID:PID` per line; blank lines
+    /// and `#` comments are ignored.
+    pub fn load_ids(&mut self, path: &Path) -> io::Result<()> {
+        let text = fs::read_to_string(path)?;
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let id = line.parse::<UsbId>().map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}:{}: {}", path.display(), n + 1, e),
+                )
+            })?;
+            self.add_id(id);
+        }
+        Ok(())
+    }
+}
+
+/// Returns the USB serial number of the port `name`, if it is a USB device
+/// that reports one.
+pub fn usb_serial_number(name: &str) -> Option<String> {
+    serialport::available_ports()
+        .ok()?
+        .into_iter()
+        .find(|p| p.port_name == name)
+        .and_then(|p| match p.port_type {
+            serialport::SerialPortType::UsbPort(pt) => pt.serial_number,
+            _ => None,
+        })
+}
+
+/// An enumerated serial port.
+#[derive(Debug, Clone)]
+pub struct PortInfo {
+    pub name: String,
+    /// Vendor and product id, for USB ports.
+    pub usb_id: Option<UsbId>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+    /// Whether the port matched as a flight controller.
+    pub is_fc: bool,
+}
+
+/// Default location of the USB id file read by the `msptest` tool,
+/// `msptest/usb-ids` in the user configuration directory.
+pub fn config_path() -> Option<PathBuf> {
+    let base = if cfg!(windows) {
+        env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|h| Path::new(&h).join(".config")))
+    }?;
+    Some(base.join("msptest").join("usb-ids"))
+}
+
+/// Returns the first enumerated serial port that looks like a flight
+/// controller, or `defdev` if none is found.
+///
+/// STM32 VCP and FTDI adaptors are always recognised; CP210x bridges are
+/// recognised if `testcvt` is set. On FreeBSD, `/dev/cuaU*` is also accepted.
+pub fn get_serial_device(defdev: &str, testcvt: bool) -> String {
+    DeviceMatcher::new(testcvt)
+        .find()
+        .unwrap_or_else(|| defdev.to_string())
+}
+
+/// Returns the candidate rates tried by [`detect_baud`] on this platform.
+pub fn autobaud_rates() -> Vec<u32> {
+    #[allow(unused_mut)]
+    let mut rates = STD_RATES.to_vec();
+    #[cfg(target_os = "linux")]
+    rates.extend_from_slice(EXTRA_RATES);
+    rates
+}
+
+/// Probes the serial device `name` at each of `rates` in turn, sending
+/// `MSG_API_VERSION` and `MSG_IDENT` (MSPv1), and returns the first rate at
+/// which a reply with a valid checksum is received within `timeout`.
+///
+/// Returns `Ok(None)` if there is no reply at any rate.
+pub fn detect_baud(
+    name: &str,
+    opts: &SerialOptions,
+    rates: &[u32],
+    timeout: Duration,
+) -> io::Result<Option<u32>> {
+    let mut probe = msp::encode_msp(msp::MSG_API_VERSION, &[]).unwrap();
+    probe.extend(msp::encode_msp(msp::MSG_IDENT, &[]).unwrap());
+    let mut inp = [0u8; 256];
+    for &baud in rates {
+        let mut sd = SerialDevice::new();
+        sd.set_timeout(Some(Duration::from_millis(50)));
+        sd.open_with(name, baud as isize, opts)?;
+        sd.clear();
+        sd.write_all(&probe)?;
+        let mut parser = MspParser::new();
+        let deadline = Instant::now() + timeout;
+        let mut found = false;
+        while !found && Instant::now() < deadline {
+            match sd.read(&mut inp) {
+                Ok(0) => break,
+                Ok(n) => {
+                    found = parser.feed(&inp[..n]).any(|m| {
+                        matches!(m.ok, MSPRes::Ok | MSPRes::Error)
+                            && (m.cmd == msp::MSG_API_VERSION || m.cmd == msp::MSG_IDENT)
+                    })
+                }
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => (),
+                Err(e) => return Err(e),
+            }
+        }
+        sd.close();
+        if found {
+            return Ok(Some(baud));
+        }
+    }
+    Ok(None)
+}
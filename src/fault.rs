@@ -0,0 +1,204 @@
+//! Fault injection, for testing behaviour on noisy links (e.g. radio
+//! telemetry) with a clean bench link.
+//!
+//! [`FaultReader`] and [`FaultWriter`] wrap a link's reader and writer and
+//! randomly drop, corrupt, duplicate and delay the bytes passing through.
+//! The faults are pseudo-random from [`FaultOptions::seed`], so the same
+//! seed and traffic give the same faults.
+
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::thread;
+use std::time::Duration;
+
+/// Fault probabilities (per byte, 0 to 1) and delay.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaultOptions {
+    /// Probability that a byte is dropped.
+    pub drop: f64,
+    /// Probability that a random bit of a byte is inverted.
+    pub flip: f64,
+    /// Probability that a byte is sent twice.
+    pub dup: f64,
+    /// Maximum delay of each read or write; the actual delay is random.
+    pub delay: Duration,
+    /// Seed for the fault sequence.
+    pub seed: u64,
+}
+
+impl Default for FaultOptions {
+    /// No faults, seed 1.
+    fn default() -> Self {
+        Self {
+            drop: 0.0,
+            flip: 0.0,
+            dup: 0.0,
+            delay: Duration::ZERO,
+            seed: 1,
+        }
+    }
+}
+
+impl fmt::Display for FaultOptions {
+    /// Formats as URI query parameters, e.g. `drop=0.01&delay=20000us&seed=1`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (k, v) in [("drop", self.drop), ("flip", self.flip), ("dup", self.dup)] {
+            if v > 0.0 {
+                write!(f, "{}={}&", k, v)?;
+            }
+        }
+        if !self.delay.is_zero() {
+            write!(f, "delay={}us&", self.delay.as_micros())?;
+        }
+        write!(f, "seed={}", self.seed)
+    }
+}
+
+// SplitMix64; small and reproducible, which is all that is needed here.
+#[derive(Debug, Clone)]
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, 1).
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && self.next_f64() < p
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Faults {
+    opts: FaultOptions,
+    rng: Rng,
+}
+
+impl Faults {
+    fn new(opts: &FaultOptions, stream: u64) -> Self {
+        Self {
+            opts: opts.clone(),
+            rng: Rng(opts.seed ^ stream),
+        }
+    }
+
+    fn apply(&mut self, inp: &[u8], out: &mut Vec<u8>) {
+        for &b in inp {
+            if self.rng.chance(self.opts.drop) {
+                continue;
+            }
+            let mut b = b;
+            if self.rng.chance(self.opts.flip) {
+                b ^= 1 << (self.rng.next_u64() % 8);
+            }
+            out.push(b);
+            if self.rng.chance(self.opts.dup) {
+                out.push(b);
+            }
+        }
+    }
+
+    fn delay(&mut self) {
+        if !self.opts.delay.is_zero() {
+            thread::sleep(self.opts.delay.mul_f64(self.rng.next_f64()));
+        }
+    }
+}
+
+/// Reader injecting faults into the data read from `inner`.
+///
+/// ```
+/// use std::io::Read;
+/// use msptest::fault::{FaultOptions, FaultReader};
+///
+/// let opts = FaultOptions { drop: 0.1, flip: 0.1, ..Default::default() };
+/// let data = [0x55u8; 1000];
+/// let mut a = Vec::new();
+/// FaultReader::new(&data[..], &opts).read_to_end(&mut a).unwrap();
+/// let mut b = Vec::new();
+/// FaultReader::new(&data[..], &opts).read_to_end(&mut b).unwrap();
+/// assert!(a.len() < 1000 && a.iter().any(|&x| x != 0x55));
+/// // Reproducible from the seed.
+/// assert_eq!(a, b);
+/// ```
+#[derive(Debug)]
+pub struct FaultReader<R> {
+    inner: R,
+    faults: Faults,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> FaultReader<R> {
+    pub fn new(inner: R, opts: &FaultOptions) -> Self {
+        Self {
+            inner,
+            faults: Faults::new(opts, 0),
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for FaultReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut inp = [0u8; 1024];
+        // A read from which every byte is dropped must not look like EOF.
+        while self.pos == self.buf.len() {
+            let lim = buf.len().clamp(1, inp.len());
+            let n = self.inner.read(&mut inp[..lim])?;
+            if n == 0 {
+                return Ok(0);
+            }
+            self.buf.clear();
+            self.pos = 0;
+            self.faults.apply(&inp[..n], &mut self.buf);
+            self.faults.delay();
+        }
+        let n = buf.len().min(self.buf.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Writer injecting faults into the data written to `inner`. Faults use a
+/// different sequence from a [`FaultReader`] with the same options.
+#[derive(Debug)]
+pub struct FaultWriter<W> {
+    inner: W,
+    faults: Faults,
+}
+
+impl<W: Write> FaultWriter<W> {
+    pub fn new(inner: W, opts: &FaultOptions) -> Self {
+        Self {
+            inner,
+            faults: Faults::new(opts, 0x5752_4954_4552),
+        }
+    }
+}
+
+impl<W: Write> Write for FaultWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut out = Vec::with_capacity(buf.len() + 8);
+        self.faults.apply(buf, &mut out);
+        self.faults.delay();
+        self.inner.write_all(&out)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,44 @@
+//! MSP (MultiWii Serial Protocol) library for INAV, Betaflight, MultiWii etc.
+//!
+//! Provides the protocol codec ([`msp`]), typed message payloads
+//! ([`messages`]), transports ([`transport`], [`serial`], [`net`]),
+//! device specification parsing ([`parse_dev`]) and serial device
+//! auto-detection ([`devices`]). [`sim`] is a simulated FC for testing
+//! without hardware. [`capture`] records link traffic, which [`pcap`]
+//! exports for Wireshark. [`client::MspClient`] provides a simple
+//! request / response API over any transport; with the `tokio` feature,
+//! [`asyncio`] provides async transports and client.
+//!
+//! ```no_run
+//! use std::io::Write;
+//! use std::thread;
+//! use msptest::{msp, net};
+//!
+//! let mut conn = net::tcp_connect("localhost", 5760).unwrap();
+//! let rd = conn.try_clone().unwrap();
+//! let (tx, rx) = crossbeam_channel::unbounded();
+//! thread::spawn(move || msp::reader(rd, tx));
+//! conn.write_all(&msp::encode_msp(msp::MSG_IDENT, &[]).unwrap()).unwrap();
+//! let msg = rx.recv().unwrap();
+//! println!("{:?}", msg);
+//! ```
+
+#[cfg(feature = "tokio")]
+pub mod asyncio;
+pub mod capture;
+pub mod client;
+pub mod devices;
+pub mod fault;
+pub mod messages;
+pub mod msp;
+pub mod net;
+pub mod parse_dev;
+pub mod pcap;
+pub mod reconnect;
+pub mod sim;
+pub mod transport;
+
+#[cfg_attr(unix, path = "serial_posix.rs")]
+#[cfg_attr(windows, path = "serial_windows.rs")]
+pub mod serial;
+pub mod serial_options;
@@ -1,7 +1,3 @@
-extern crate crossbeam_channel;
-extern crate getopts;
-extern crate sys_info;
-
 use crossbeam_channel::{bounded, select, tick, unbounded, Receiver};
 use crossterm::{
     cursor::*,
@@ -15,35 +11,28 @@ use crossterm::{
 };
 use getopts::Options;
 use iota::iota;
-use msp::MSPMsg;
-use std::convert::TryInto;
+use msptest::capture::{self, Capture};
+use msptest::messages::{self, DecodeError, Message};
+use msptest::msp::MSPMsg;
+use msptest::reconnect::Backoff;
+use msptest::transport::{Endpoint, Transport};
+use msptest::{devices, msp, parse_dev, pcap};
+use std::collections::HashSet;
 use std::env;
 use std::io;
 use std::io::stdout;
 use std::io::*;
+use std::path::Path;
 use std::thread;
 use std::time;
 use std::time::Duration;
 use std::time::Instant;
 use sys_info::*;
-use std::net::TcpStream;
-
-#[cfg(all(unix))]
-use std::net::UdpSocket;
-#[cfg(all(unix))]
-use std::fs::File;
-#[cfg(all(unix))]
-use std::os::fd::FromRawFd;
-#[cfg(all(unix))]
-use std::os::fd::IntoRawFd;
-
-mod parse_dev;
 
-mod msp;
-
-#[cfg_attr(unix, path = "serial_posix.rs")]
-#[cfg_attr(windows, path = "serial_windows.rs")]
-mod serial;
+mod bridge;
+mod proxy;
+mod simulate;
+mod sniff;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -228,12 +217,38 @@ fn clean_exit(rows: u16) {
 
 fn print_usage(program: &str, opts: &Options) {
     let brief = format!(
-        "Usage: {} [options] [device-node|URI]\nVersion: {}",
+        "Usage: {0} [options] [device-node|URI]\n       {0} proxy [device-node|URI] [tcp-listen://...|udp-listen://...]\n       {0} bridge device-node|URI device-node|URI\n       {0} pcap capture-file pcapng-file\nVersion: {1}",
         program, VERSION
     );
     print!("{}", opts.usage(&brief));
 }
 
+fn list_ports(matcher: &devices::DeviceMatcher) -> io::Result<()> {
+    let ports = matcher.list()?;
+    println!(
+        "{:<20} {:<10} {:<32} {:<20} FC",
+        "Port", "VID:PID", "Manufacturer / Product", "Serial"
+    );
+    for p in ports {
+        let id = p.usb_id.map_or("-".to_string(), |id| id.to_string());
+        let desc = match (p.manufacturer, p.product) {
+            (Some(m), Some(pr)) => format!("{} / {}", m, pr),
+            (Some(m), None) => m,
+            (None, Some(pr)) => pr,
+            (None, None) => "-".to_string(),
+        };
+        println!(
+            "{:<20} {:<10} {:<32} {:<20} {}",
+            p.name,
+            id,
+            desc,
+            p.serial_number.unwrap_or("-".to_string()),
+            if p.is_fc { "yes" } else { "no" }
+        );
+    }
+    Ok(())
+}
+
 fn ctrl_channel() -> std::result::Result<Receiver<u8>, io::Error> {
     let (sender, receiver) = bounded(5);
     thread::spawn(move || loop {
@@ -274,50 +289,63 @@ fn ctrl_channel() -> std::result::Result<Receiver<u8>, io::Error> {
     Ok(receiver)
 }
 
-pub fn get_serial_device(defdev: &str, testcvt: bool) -> String {
-    match serialport::available_ports() {
-        Ok(ports) => {
-            for p in ports {
-                match &p.port_type {
-                    serialport::SerialPortType::UsbPort(pt) => {
-                        if (pt.vid == 0x0483 && pt.pid == 0x5740)
-                            || (pt.vid == 0x0403 && pt.pid == 0x6001)
-                            || (testcvt && (pt.vid == 0x10c4 && pt.pid == 0xea60))
-                        {
-                            return p.port_name.clone();
-                        }
-                    }
-                    _ => {
-                        if std::env::consts::OS == "freebsd" && &p.port_name[0..9] == "/dev/cuaU" {
-                            return p.port_name.clone();
-                        }
-                    }
-                }
+fn wait_for_key(cc: &Receiver<u8>, tot: u64, itm: u32) -> bool {
+    let ticks = tick(Duration::from_millis(tot));
+    let mut j = 0;
+    loop {
+        select! {
+            recv(ticks) -> _ => {
+            j += 1;
+            if j == itm {
+                return true;
+            }
+            }
+            recv(cc) -> res => {
+            if let Ok(x) = res  {
+                if x == b'Q' { return false}
+            }
             }
-            defdev.to_string()
         }
-        Err(_e) => defdev.to_string(),
     }
 }
 
-fn wait_for_key (cc: &Receiver<u8>, tot: u64, itm: u32) -> bool {
-    let ticks = tick(Duration::from_millis(tot));
-    let mut j = 0;
-    loop {
-	select! {
-	    recv(ticks) -> _ => {
-		j += 1;
-		if j == itm {
-		    return true;
-		}
-	    }
-	    recv(cc) -> res => {
-		if let Ok(x) = res  {
-		    if x == b'Q' { return false}
-		}
-	    }
-	}
-    }
+// Shows the retry status and waits for the backoff delay; false if the
+// user quit.
+fn wait_to_reconnect(
+    cc: &Receiver<u8>,
+    backoff: &mut Backoff,
+    connected: bool,
+    ep: &Endpoint,
+    e: &io::Error,
+) -> Result<bool> {
+    let delay = backoff.next_delay();
+    let what = if connected {
+        "reconnecting"
+    } else {
+        "connecting"
+    };
+    outvalue(
+        IY_PORT,
+        &format!("{} {} (attempt {}): {}", ep, what, backoff.attempt(), e),
+    )?;
+    Ok(wait_for_key(cc, 50, (delay.as_millis() / 50).max(1) as u32))
+}
+
+// msptest pcap in.cap out.pcapng
+fn export_pcap(args: &[String]) -> Result<()> {
+    let [inp, out] = args else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "expected a capture file and a pcapng file",
+        ));
+    };
+    let cap = capture::open(Path::new(inp))
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", inp, e)))?;
+    let f = std::fs::File::create(out)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", out, e)))?;
+    let (frames, crc) = pcap::export(cap, BufWriter::new(f))?;
+    println!("{} frames ({} CRC errors)", frames, crc);
+    Ok(())
 }
 
 fn main() -> Result<()> {
@@ -329,6 +357,33 @@ fn main() -> Result<()> {
     let mut opts = Options::new();
     opts.optflag("s", "slow", "slow mode");
     opts.optflag("1", "once", "Single iteration, then exit");
+    opts.optflag(
+        "S",
+        "sniff",
+        "Passively decode traffic in both directions (one or more devices)",
+    );
+    opts.optflag("b", "autobaud", "Detect the serial baud rate");
+    opts.optflag("l", "list", "List serial ports and exit");
+    opts.optmulti(
+        "",
+        "usb-id",
+        "Additional FC USB id for auto-detection (repeatable)",
+        "VID:PID",
+    );
+    opts.optopt(
+        "",
+        "usb-serial",
+        "Auto-detect the device with this USB serial number",
+        "SERIAL",
+    );
+    opts.optopt("", "simulate", "Run a simulated FC (inav, inav19, multiwii, betaflight) on the given tcp-listen://, udp-listen:// or pty endpoints", "PERSONA");
+    opts.optopt("", "capture", "Record all link traffic to FILE", "FILE");
+    opts.optopt(
+        "",
+        "pcap",
+        "Record the MSP frames to FILE as pcapng",
+        "FILE",
+    );
     opts.optflag("v", "version", "Show version");
     opts.optflag("h", "help", "print this help menu");
 
@@ -361,103 +416,273 @@ fn main() -> Result<()> {
         once = true;
     }
 
+    if let Some(p) = matches.opt_str("simulate") {
+        let res = p
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+            .and_then(|persona| simulate::run(persona, &matches.free));
+        if let Err(e) = res {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let autobaud = matches.opt_present("b");
+
+    let mut matcher = devices::DeviceMatcher::new(true);
+    if let Some(path) = devices::config_path() {
+        match matcher.load_ids(&path) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    for id in matches.opt_strs("usb-id") {
+        match id.parse() {
+            Ok(id) => matcher.add_id(id),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    matcher.set_serial(matches.opt_str("usb-serial"));
+
+    if matches.opt_present("l") {
+        if let Err(e) = list_ports(&matcher) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if matches.free.first().is_some_and(|a| a == "proxy") {
+        let autodev = matcher.find().unwrap_or_else(|| "auto".to_string());
+        if let Err(e) = proxy::run(&matches.free[1..], &autodev) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if matches.free.first().is_some_and(|a| a == "bridge") {
+        let autodev = matcher.find().unwrap_or_else(|| "auto".to_string());
+        if let Err(e) = bridge::run(&matches.free[1..], &autodev) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if matches.free.first().is_some_and(|a| a == "pcap") {
+        if let Err(e) = export_pcap(&matches.free[1..]) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if matches.opt_present("S") {
+        let devs = if matches.free.is_empty() {
+            vec![matcher.find().unwrap_or_else(|| "auto".to_string())]
+        } else {
+            matches.free.clone()
+        };
+        if let Err(e) = sniff::run(&devs) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
     let defdev = if !matches.free.is_empty() {
         &matches.free[0]
     } else {
         "auto"
     };
 
-    let encode_msp_vers = |cmd, payload, version| match version {
-        1 => msp::encode_msp(cmd, payload),
-        _ => msp::encode_msp2(cmd, payload),
+    let uep = match defdev {
+        "auto" => None,
+        _ => match parse_dev::parse_uri_dev(defdev) {
+            Ok(ep) => Some(ep),
+            Err(e) => {
+                eprintln!("{}: {}", defdev, e);
+                std::process::exit(1);
+            }
+        },
+    };
+
+    let mut captures = Vec::new();
+    for (opt, create) in [
+        (
+            "capture",
+            Capture::create as fn(&Path) -> io::Result<Capture>,
+        ),
+        ("pcap", Capture::create_pcap),
+    ] {
+        if let Some(path) = matches.opt_str(opt) {
+            match create(Path::new(&path)) {
+                Ok(c) => captures.push(c),
+                Err(e) => {
+                    eprintln!("{}: {}", path, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+
+    let encode_msp_vers = |cmd, payload, version| {
+        match version {
+            // MSPv1 can't carry commands above 255.
+            1 if cmd <= 255 => msp::encode_msp(cmd, payload),
+            _ => msp::encode_msp2(cmd, payload),
+        }
+        .unwrap()
     };
 
     let ctrl_c_events = ctrl_channel().unwrap();
 
     let (mut cols, mut rows) = size()?;
 
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        _ = disable_raw_mode();
+        _ = execute!(stdout(), Show);
+        hook(info);
+    }));
+
     enable_raw_mode()?;
     execute!(stdout(), Hide)?;
     execute!(stdout(), Clear(ClearType::All))?;
 
-    let mut sd = serial::SerialDevice::new();
-    'a:
-    loop {
-	let thr: thread::JoinHandle<_>;
-	let mut dtyp: u8 = 0;
-	let pname: String;
-	let param: u32;
-
-	match defdev {
-	    "auto" => {
-		param = 115200;
-		pname = get_serial_device(defdev, true);
-	    },
-	    _ => (pname, param, dtyp) = parse_dev::parse_uri_dev(defdev),
+    let mut backoff = Backoff::default();
+    let mut connected = false;
+    let mut usb_serial = matches.opt_str("usb-serial");
+
+    // Kept across sessions, so that a listening transport accepts the next peer.
+    let mut cached: Option<(Endpoint, Box<dyn Transport>)> = None;
+
+    'a: loop {
+        let mut ep = match uep {
+            Some(ref ep) => ep.clone(),
+            None => Endpoint::Serial {
+                name: matcher.find().unwrap_or_else(|| defdev.to_string()),
+                baud: 115200,
+                opts: Default::default(),
+            },
         };
+        // Follow a USB device that has re-enumerated under a new name.
+        if usb_serial.is_some() {
+            if let Endpoint::Serial { ref mut name, .. } = ep {
+                if let Some(n) = matcher.find() {
+                    *name = n;
+                }
+            }
+        }
 
         redraw(cols, rows)?;
-	let (snd, rcv) = unbounded();
-
-	let mut strm: Box<dyn Write> = if dtyp == 0 {
-            match sd.open(&pname, param as isize) {
-		Ok(_) => {
-		    sd.clear();
-		    let rd = sd.clone();
-		    thr = thread::spawn(move || {
-			msp::reader(Box::new(rd), snd);
-		    });
-		},
-		Err(_e) => {
-		    if wait_for_key(&ctrl_c_events, 50, 20) {
-			continue 'a;
-		    } else {
-			break 'a;
-		    }
-		}
+
+        let mut detected = None;
+        if autobaud {
+            if let Endpoint::Serial {
+                ref name,
+                ref mut baud,
+                ref opts,
+            } = ep
+            {
+                outvalue(IY_PORT, &format!("{} (detecting baud rate)", name))?;
+                match devices::detect_baud(
+                    name,
+                    opts,
+                    &devices::autobaud_rates(),
+                    Duration::from_millis(500),
+                ) {
+                    Ok(Some(b)) => {
+                        *baud = b;
+                        detected = Some(true);
+                    }
+                    Ok(None) => detected = Some(false),
+                    Err(e) => {
+                        if wait_to_reconnect(&ctrl_c_events, &mut backoff, connected, &ep, &e)? {
+                            continue 'a;
+                        } else {
+                            break 'a;
+                        }
+                    }
+                }
+            }
+        }
+        let (snd, rcv) = unbounded();
+
+        let tp = match cached.take() {
+            Some((cep, tp)) if cep == ep => tp,
+            _ => ep.transport(),
+        };
+        if matches!(ep, Endpoint::TcpListen { .. } | Endpoint::UdpListen { .. }) {
+            outvalue(IY_PORT, &format!("{} (waiting for peer)", ep))?;
+        }
+        // Opened on another thread, as it may wait indefinitely for a peer.
+        let (otx, orx) = bounded(1);
+        thread::spawn(move || {
+            let mut tp = tp;
+            let res = tp.open().and_then(|_| Ok((tp.reader()?, tp.writer()?)));
+            _ = otx.send((tp, res));
+        });
+        let (mut tp, res) = loop {
+            select! {
+            recv(orx) -> r => break r.unwrap(),
+            recv(ctrl_c_events) -> res => {
+                if let Ok(b'Q') = res {
+                clean_exit(rows);
+                }
+            }
+            }
+        };
+        let (mut rd, mut strm) = match res {
+            Ok(rw) => rw,
+            Err(e) => {
+                cached = Some((ep.clone(), tp));
+                if wait_to_reconnect(&ctrl_c_events, &mut backoff, connected, &ep, &e)? {
+                    continue 'a;
+                } else {
+                    break 'a;
+                }
+            }
+        };
+        backoff.reset();
+        connected = true;
+        for cap in &captures {
+            let baud = match ep {
+                Endpoint::Serial { baud, .. } => baud,
+                _ => 0,
+            };
+            _ = cap.session(&tp.description(), baud);
+            rd = Box::new(cap.reader(rd));
+            strm = Box::new(cap.writer(strm));
+        }
+        if let Endpoint::Serial { ref name, .. } = ep {
+            if usb_serial.is_none() {
+                usb_serial = devices::usb_serial_number(name);
+                matcher.set_serial(usb_serial.clone());
             }
-	    Box::new(sd.clone())
-	} else if dtyp == 1 {
-	    let conn: TcpStream;
-	    match TcpStream::connect((pname.as_str(), param as u16)) {
-		Ok(s) => {
-		    conn = s;
-		    _ = conn.set_nodelay(true);
-		    let rstream = conn.try_clone().unwrap();
-		    thr = thread::spawn(move || {
-			msp::reader(Box::new(rstream), snd);
-		    });
-		},
-		Err(_e) => {
-		    if wait_for_key(&ctrl_c_events, 50, 20) {
-			continue 'a;
-		    } else {
-			break 'a;
-		    }
-		}
-	    }
-	    Box::new(conn)
-	} else {
-#[cfg(all(unix))]
-	    {
-	    let socket = UdpSocket::bind("[::]:0")?;
-	    socket.connect(&format!("{}:{}", pname.as_str(), param as u16)).expect("connect function failed");
-	    let f = unsafe { File::from_raw_fd(socket.into_raw_fd()) };
-	    let rsock = f.try_clone().unwrap();
-		    thr = thread::spawn(move || {
-			msp::reader(Box::new(rsock), snd);
-		    });
-	    Box::new(f)
-	    }
-
-#[cfg(not(unix))]
-	    break 'a;
-	};
-
-        outvalue(IY_PORT, &format!("{}:{}", &pname, param))?;
+        }
+        let thr = thread::spawn(move || {
+            msp::reader(rd, snd);
+        });
+
+        let pname = match detected {
+            Some(true) => format!("{} (autobaud)", tp.description()),
+            Some(false) => format!("{} (autobaud failed)", tp.description()),
+            None => tp.description(),
+        };
+        outvalue(IY_PORT, &pname)?;
 
         let mut nto = 0;
-        _ = strm.write(&msp::encode_msp(msp::MSG_IDENT, &[]));
+        _ = strm.write(&encode_msp_vers(msp::MSG_IDENT, &[], 1));
         let ticks = tick(Duration::from_millis(100));
         let mut st = Instant::now();
         let mut mtimer = Instant::now();
@@ -465,267 +690,238 @@ fn main() -> Result<()> {
         let mut msgcnt = 0;
         let mut e_bad = 0;
         let mut e_crc = 0;
+        let mut e_dec = 0;
+        let mut last_dec: Option<DecodeError> = None;
+        let mut unsupported = HashSet::new();
 
         'b: loop {
             select! {
-                recv(ticks) -> _ => {
-                    if mtimer.elapsed() > Duration::from_millis(5000) {
-                        vers  = 1;
-                        nto += 1;
-                        outvalue(IY_RATE, &format!("Timeout ({})", nto))?;
-                        mtimer = Instant::now();
-                        _ = strm.write(&msp::encode_msp(msp::MSG_IDENT, &[]));
-                    }
+                    recv(ticks) -> _ => {
+                        if mtimer.elapsed() > Duration::from_millis(5000) {
+                            vers  = 1;
+                            nto += 1;
+                            outvalue(IY_RATE, &format!("Timeout ({})", nto))?;
+                            mtimer = Instant::now();
+                            _ = strm.write(&encode_msp_vers(msp::MSG_IDENT, &[], 1));
+                        }
 
-                    if msgcnt > 0 {
-			let dura = st.elapsed();
-			let duras: f64 = dura.as_secs() as f64 + dura.subsec_nanos() as f64 / 1e9;
-			let rate = msgcnt as f64 / duras;
-			outvalue(
-			    IY_RATE,
-			    &format!("{} messages in {:.1}s ({:.1}/s) (unknown: {}, crc {})", msgcnt, duras, rate, e_bad, e_crc))?;
-                    }
-		}
+                        if msgcnt > 0 {
+                let dura = st.elapsed();
+                let duras: f64 = dura.as_secs() as f64 + dura.subsec_nanos() as f64 / 1e9;
+                let rate = msgcnt as f64 / duras;
+                let mut stats = format!("{} messages in {:.1}s ({:.1}/s) (unknown: {}, crc {}, decode {})", msgcnt, duras, rate, e_bad, e_crc, e_dec);
+                if let Some(ref e) = last_dec {
+                    stats += &format!(" [{}]", e);
+                }
+                outvalue(IY_RATE, &stats)?;
+                        }
+            }
 
-                recv(ctrl_c_events) -> res => {
-                    if let Ok(x) = res {
-			if x == b'Q' { clean_exit(rows);}
-			refresh = true;
+                    recv(ctrl_c_events) -> res => {
+                        if let Ok(x) = res {
+                if x == b'Q' { clean_exit(rows);}
+                refresh = true;
+                        }
                     }
-                }
 
-                recv(rcv) -> res => {
-                    let mut nxt: u16;
-                    mtimer = Instant::now();
-                    match res {
-                        Ok(x) => {
-			    let _last = x.cmd;
-                            match x.ok {
-                                msp::MSPRes::Ok => {
-                                    if let Some(i) = handle_msp(x, &mut vers, slow, once) {
-					if i == 0 {
-					    continue 'b;
-					} else {
-					    if msgcnt == 0 {
-						st = Instant::now();
-						e_crc = 0;
-						e_bad = 0;
-					    }
-                                            nxt = i;
-					    msgcnt += 1;
-					}
-                                    } else {
-                                        break 'a;
-                                    }
-                                },
-                                msp::MSPRes::Crc => {
-				    e_crc += 1;
-                                    nxt = msp::MSG_IDENT;
-                                },
-                                msp::MSPRes::Dirn => {
-				    e_bad += 1;
-                                    nxt = match x.cmd {
-                                        msp::MSG_IDENT => msp::MSG_NAME,
-                                        msp::MSG_NAME => msp::MSG_API_VERSION,
-                                        msp::MSG_API_VERSION => msp::MSG_FC_VARIANT,
-                                        msp::MSG_FC_VARIANT => msp::MSG_FC_VERSION,
-                                        msp::MSG_FC_VERSION => msp::MSG_BUILD_INFO,
-                                        msp::MSG_BUILD_INFO => msp::MSG_BOARD_INFO,
-                                        msp::MSG_BOARD_INFO => {
-                                            outvalue(IY_BOARD, "MultiWii")?;
-                                            msp::MSG_WP_GETINFO
-                                        },
-                                        msp::MSG_WP_GETINFO => msp::MSG_ANALOG,
-                                        msp::MSG_MISC2 => msp::MSG_ANALOG,
-                                        msp::MSG_INAV_STATUS => msp::MSG_STATUS_EX,
-                                        msp::MSG_STATUS_EX =>  msp::MSG_RAW_GPS,
-                                            _ => msp::MSG_IDENT,
-                                    };
-                                },
-                                msp::MSPRes::Fail => {
-                                    thr.join().unwrap();
-				    break 'b ;
-                                },
-                            }
-			    if nxt == msp::MSG_IDENT  {
-				vers = 1;
-				msgcnt = 0;
-			    }
-			    if refresh {
-				refresh  = false;
-				nxt = msp::MSG_IDENT;
-				(cols, rows) = size()?;
-				execute!(stdout(), Clear(ClearType::All))?;
-				redraw(cols, rows)?;
-				outvalue(IY_PORT, &pname)?;
-			    }
-			    match strm.write(&encode_msp_vers(nxt, &[], vers)) {
-				Ok(_) => (),
-				Err(_) => break 'b,
-			    }
-                        },
+                    recv(rcv) -> res => {
+                        let mut nxt: u16;
+                        mtimer = Instant::now();
+                        match res {
+                            Ok(x) => {
+                                match x.ok {
+                                    msp::MSPRes::Ok => {
+                        match messages::decode(x.cmd, &x.data) {
+                        Ok(m) => show_msg(&m, &mut vers)?,
+                        Err(DecodeError::Unknown(_)) => (),
                         Err(e) => {
-			    eprintln!("Recv-err {}",e);
-                            thr.join().unwrap();
-			    break 'b
-			},
+                            e_dec += 1;
+                            last_dec = Some(e);
+                        }
+                        }
+                                        if let Some(i) = handle_msp(&x, vers, slow, once) {
+                        if i == 0 {
+                            continue 'b;
+                        } else {
+                            if msgcnt == 0 {
+                            st = Instant::now();
+                            e_crc = 0;
+                            e_bad = 0;
+                            e_dec = 0;
+                            last_dec = None;
+                            }
+                                                nxt = i;
+                            msgcnt += 1;
+                        }
+                                        } else {
+                                            break 'a;
+                                        }
+                                    },
+                                    msp::MSPRes::Crc => {
+                        e_crc += 1;
+                                        nxt = msp::MSG_IDENT;
+                                    },
+                                    msp::MSPRes::Error => {
+                        e_bad += 1;
+                        if x.cmd != msp::MSG_IDENT {
+                        unsupported.insert(x.cmd);
+                        }
+                        if x.cmd == msp::MSG_BOARD_INFO {
+                        outvalue(IY_BOARD, "MultiWii")?;
+                        }
+                                        nxt = fallback_cmd(x.cmd);
+                                    },
+                                    msp::MSPRes::Request => continue 'b,
+                                    msp::MSPRes::Fail => break 'b,
+                                }
+                    while unsupported.contains(&nxt) {
+                    nxt = fallback_cmd(nxt);
+                    }
+                    if nxt == msp::MSG_IDENT  {
+                    vers = 1;
+                    msgcnt = 0;
+                    }
+                    if refresh {
+                    refresh  = false;
+                    nxt = msp::MSG_IDENT;
+                    (cols, rows) = size()?;
+                    execute!(stdout(), Clear(ClearType::All))?;
+                    redraw(cols, rows)?;
+                    outvalue(IY_PORT, &pname)?;
+                    }
+                    match strm.write_all(&encode_msp_vers(nxt, &[], vers)) {
+                    Ok(_) => (),
+                    Err(_) => break 'b,
+                    }
+                            },
+                            Err(e) => {
+                    eprintln!("Recv-err {}",e);
+                    break 'b
+                },
+                        }
                     }
                 }
-            }
         }
+        // Closing the transport wakes the reader if it is still blocked.
+        tp.close();
+        _ = thr.join();
+        cached = Some((ep, tp));
     }
     clean_exit(rows);
     Ok(())
 }
 
-fn handle_msp(x: MSPMsg, vers: &mut u8, slow: bool, once: bool) -> Option<u16> {
-    let nxt: Option<u16>;
-    match x.cmd {
-        msp::MSG_IDENT => {
-            if x.len > 0 {
-                outvalue(IY_MW, &format!("MSP Vers: {}, (MSP v{})", x.data[0], *vers)).unwrap();
-            }
-            nxt = Some(msp::MSG_NAME)
-        }
-        msp::MSG_NAME => {
-            outvalue(IY_NAME, &String::from_utf8_lossy(&x.data)).unwrap();
-            nxt = Some(msp::MSG_API_VERSION)
-        }
-        msp::MSG_API_VERSION => {
-            if x.len > 2 {
-                if x.data[1] > 1 && x.data[2] > 0 && *vers == 1 {
-                    *vers = 2;
+// Command to poll instead of `cmd` when the FC does not support it.
+// Unsupported commands are skipped until the next connection.
+fn fallback_cmd(cmd: u16) -> u16 {
+    match cmd {
+        msp::MSG_IDENT => msp::MSG_NAME,
+        msp::MSG_NAME => msp::MSG_API_VERSION,
+        msp::MSG_API_VERSION => msp::MSG_FC_VARIANT,
+        msp::MSG_FC_VARIANT => msp::MSG_FC_VERSION,
+        msp::MSG_FC_VERSION => msp::MSG_BUILD_INFO,
+        msp::MSG_BUILD_INFO => msp::MSG_BOARD_INFO,
+        msp::MSG_BOARD_INFO => msp::MSG_WP_GETINFO,
+        msp::MSG_WP_GETINFO => msp::MSG_ANALOG,
+        msp::MSG_MISC2 => msp::MSG_ANALOG,
+        msp::MSG_INAV_STATUS => msp::MSG_STATUS_EX,
+        msp::MSG_STATUS_EX => msp::MSG_RAW_GPS,
+        _ => msp::MSG_IDENT,
+    }
+}
+
+// Next command in the polling sequence after a valid reply to `x`.
+fn handle_msp(x: &MSPMsg, vers: u8, slow: bool, once: bool) -> Option<u16> {
+    let nxt = match x.cmd {
+        msp::MSG_IDENT => msp::MSG_NAME,
+        msp::MSG_NAME => msp::MSG_API_VERSION,
+        msp::MSG_API_VERSION => msp::MSG_FC_VARIANT,
+        msp::MSG_FC_VARIANT => msp::MSG_FC_VERSION,
+        msp::MSG_FC_VERSION => msp::MSG_BUILD_INFO,
+        msp::MSG_BUILD_INFO => msp::MSG_BOARD_INFO,
+        msp::MSG_BOARD_INFO => msp::MSG_WP_GETINFO,
+        msp::MSG_WP_GETINFO | msp::MSG_RAW_GPS => {
+            if x.cmd == msp::MSG_RAW_GPS {
+                if once {
+                    return None;
+                }
+                if slow {
+                    thread::sleep(time::Duration::from_millis(1000));
                 }
-                outvalue(
-                    IY_APIV,
-                    &format!("{}.{} (MSP v{})", x.data[1], x.data[2], *vers),
-                )
-                .unwrap();
-            }
-            nxt = Some(msp::MSG_FC_VARIANT)
-        }
-        msp::MSG_FC_VARIANT => {
-            outvalue(IY_FC, &String::from_utf8_lossy(&x.data[0..4])).unwrap();
-            nxt = Some(msp::MSG_FC_VERSION)
-        }
-        msp::MSG_FC_VERSION => {
-            outvalue(
-                IY_FCVERS,
-                &format!("{}.{}.{}", x.data[0], x.data[1], x.data[2]),
-            )
-            .unwrap();
-            nxt = Some(msp::MSG_BUILD_INFO)
-        }
-        msp::MSG_BUILD_INFO => {
-            if x.len > 19 {
-                let txt = format!(
-                    "{} {} ({})",
-                    &String::from_utf8_lossy(&x.data[0..11]),
-                    &String::from_utf8_lossy(&x.data[11..19]),
-                    &String::from_utf8_lossy(&x.data[19..])
-                );
-                outvalue(IY_BUILD, &txt).unwrap();
             }
-            nxt = Some(msp::MSG_BOARD_INFO)
-        }
-        msp::MSG_BOARD_INFO => {
-            let board = if x.len > 8 {
-                String::from_utf8_lossy(&x.data[9..])
+            if vers == 2 {
+                msp::MSG_MISC2
             } else {
-                String::from_utf8_lossy(&x.data[0..4])
-            };
-            outvalue(IY_BOARD, &board).unwrap();
-            nxt = Some(msp::MSG_WP_GETINFO)
+                msp::MSG_ANALOG
+            }
         }
+        msp::MSG_MISC2 => msp::MSG_ANALOG2,
+        msp::MSG_ANALOG => msp::MSG_STATUS_EX,
+        msp::MSG_ANALOG2 => msp::MSG_INAV_STATUS,
+        msp::MSG_INAV_STATUS | msp::MSG_STATUS_EX => msp::MSG_RAW_GPS,
+        msp::MSG_DEBUGMSG => 0,
+        _ => msp::MSG_IDENT,
+    };
+    Some(nxt)
+}
 
-        msp::MSG_WP_GETINFO => {
+fn show_msg(m: &Message, vers: &mut u8) -> Result<()> {
+    match m {
+        Message::Ident(i) => outvalue(IY_MW, &format!("MSP Vers: {}, (MSP v{})", i.version, *vers)),
+        Message::Name(n) => outvalue(IY_NAME, &n.name),
+        Message::ApiVersion(a) => {
+            if a.major > 1 && a.minor > 0 && *vers == 1 {
+                *vers = 2;
+            }
             outvalue(
-                IY_WPINFO,
-                &format!("{} of {}, valid {}", x.data[3], x.data[1], (x.data[2] == 1)),
+                IY_APIV,
+                &format!("{}.{} (MSP v{})", a.major, a.minor, *vers),
             )
-            .unwrap();
-            nxt = if *vers == 2 {
-                Some(msp::MSG_MISC2)
-            } else {
-                Some(msp::MSG_ANALOG)
-            };
-        }
-        msp::MSG_MISC2 => {
-            let uptime = u32::from_le_bytes(x.data[0..4].try_into().unwrap());
-            outvalue(IY_UPTIME, &format!("{}s", uptime)).unwrap();
-            nxt = Some(msp::MSG_ANALOG2)
         }
-
-        msp::MSG_ANALOG => {
-            let volts: f32 = x.data[0] as f32 / 10.0;
-            let amps: f32 = u16::from_le_bytes(x.data[5..7].try_into().unwrap()) as f32 / 100.0;
-            outvalue(IY_ANALOG, &format!("{:.1} volts, {:2} amps", volts, amps)).unwrap();
-            nxt = Some(msp::MSG_STATUS_EX)
-        }
-
-        msp::MSG_ANALOG2 => {
-            let volts: f32 = u16::from_le_bytes(x.data[1..3].try_into().unwrap()) as f32 / 100.0;
-            let amps: f32 = u16::from_le_bytes(x.data[3..5].try_into().unwrap()) as f32 / 100.0;
-            outvalue(IY_ANALOG, &format!("{:.1} volts, {:2} amps", volts, amps)).unwrap();
-            nxt = Some(msp::MSG_INAV_STATUS)
-        }
-
-        msp::MSG_INAV_STATUS => {
-            let armf = u32::from_le_bytes(x.data[9..13].try_into().unwrap());
-            let s = get_armfails(armf);
-            outvalue(IY_ARM, &s).unwrap();
-            nxt = Some(msp::MSG_RAW_GPS);
+        Message::FcVariant(f) => outvalue(IY_FC, &f.variant),
+        Message::FcVersion(f) => {
+            outvalue(IY_FCVERS, &format!("{}.{}.{}", f.major, f.minor, f.patch))
         }
-
-        msp::MSG_STATUS_EX => {
-            let armf = u16::from_le_bytes(x.data[13..15].try_into().unwrap());
-            let s = get_armfails(armf as u32);
-            outvalue(IY_ARM, &s).unwrap();
-            nxt = Some(msp::MSG_RAW_GPS);
+        Message::BuildInfo(b) => outvalue(
+            IY_BUILD,
+            &format!("{} {} ({})", b.date, b.time, b.git_revision),
+        ),
+        Message::BoardInfo(b) => {
+            if b.target_name.is_empty() {
+                outvalue(IY_BOARD, &b.board_id)
+            } else {
+                outvalue(IY_BOARD, &b.target_name)
+            }
         }
-
-        msp::MSG_RAW_GPS => {
-            let fix = x.data[0];
-            let nsat = x.data[1];
-            let lat: f32 = i32::from_le_bytes(x.data[2..6].try_into().unwrap()) as f32 / 1e7;
-            let lon: f32 = i32::from_le_bytes(x.data[6..10].try_into().unwrap()) as f32 / 1e7;
-            let alt = i16::from_le_bytes(x.data[10..12].try_into().unwrap());
-            let spd: f32 = u16::from_le_bytes(x.data[12..14].try_into().unwrap()) as f32 / 100.0;
-            let cog: f32 = u16::from_le_bytes(x.data[14..16].try_into().unwrap()) as f32 / 10.0;
+        Message::WpInfo(w) => outvalue(
+            IY_WPINFO,
+            &format!("{} of {}, valid {}", w.count, w.max_waypoints, w.valid),
+        ),
+        Message::Misc2(m) => outvalue(IY_UPTIME, &format!("{}s", m.uptime)),
+        Message::Analog(a) => outvalue(
+            IY_ANALOG,
+            &format!("{:.1} volts, {:2} amps", a.volts, a.amps),
+        ),
+        Message::Analog2(a) => outvalue(
+            IY_ANALOG,
+            &format!("{:.1} volts, {:2} amps", a.volts, a.amps),
+        ),
+        Message::InavStatus(s) => outvalue(IY_ARM, &get_armfails(s.arming_flags)),
+        Message::StatusEx(s) => outvalue(IY_ARM, &get_armfails(s.arming_flags as u32)),
+        Message::RawGps(g) => {
             let mut s = format!(
                 "fix {}, sats {}, {:.6}° {:.6}° {}m, {:.0}m/s {:.0}°",
-                fix, nsat, lat, lon, alt, spd, cog
+                g.fix, g.num_sat, g.lat, g.lon, g.alt, g.speed, g.course
             );
-            if x.len > 16 {
-                let hdop: f32 =
-                    u16::from_le_bytes(x.data[16..18].try_into().unwrap()) as f32 / 100.0;
-                let s1 = format!(" hdop {:.2}", hdop);
-                s = s + &s1;
-            }
-
-            outvalue(IY_GPS, &s).unwrap();
-            nxt = if once {
-                None
-            } else if *vers == 2 {
-                Some(msp::MSG_MISC2)
-            } else {
-                Some(msp::MSG_ANALOG)
-            };
-            if slow {
-                thread::sleep(time::Duration::from_millis(1000));
+            if let Some(hdop) = g.hdop {
+                s += &format!(" hdop {:.2}", hdop);
             }
+            outvalue(IY_GPS, &s)
         }
-        msp::MSG_DEBUGMSG => {
-            let s = String::from_utf8_lossy(&x.data);
-	    let s = str::replace(&s, &['\r','\n','\x00'], "");
-            outvalue(IY_DEBUG, &s).unwrap();
-            nxt = Some(0)
-        }
-        _ => {
-            nxt = Some(msp::MSG_IDENT)
+        Message::DebugMsg(d) => {
+            outvalue(IY_DEBUG, &str::replace(&d.text, ['\r', '\n', '\x00'], ""))
         }
     }
-    nxt
 }
 
 fn get_armfails(reason: u32) -> String {
@@ -765,11 +961,11 @@ fn get_armfails(reason: u32) -> String {
     ];
 
     let s: String = if reason < 0x40 {
-	if reason & (1<<2) != 0 {
-	    "Armed".to_string()
-	} else {
-	    "Ready to arm".to_string()
-	}
+        if reason & (1 << 2) != 0 {
+            "Armed".to_string()
+        } else {
+            "Ready to arm".to_string()
+        }
     } else {
         let mut v: Vec<String> = Vec::new();
         for (i, e) in ARMFAILS.iter().enumerate() {
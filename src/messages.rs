@@ -0,0 +1,809 @@
+//! Typed MSP message catalogue.
+//!
+//! Each supported command has a payload struct implementing [`Payload`],
+//! which decodes (with length checks) and encodes the response payload and
+//! applies the protocol's field scaling. [`decode`] dispatches on the command
+//! id of a received frame.
+
+use crate::msp;
+
+/// Known MSP commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Command {
+    ApiVersion,
+    FcVariant,
+    FcVersion,
+    BoardInfo,
+    BuildInfo,
+    Name,
+    WpGetInfo,
+    Ident,
+    RawGps,
+    Analog,
+    StatusEx,
+    DebugMsg,
+    InavStatus,
+    Analog2,
+    Misc2,
+}
+
+impl Command {
+    pub const ALL: [Command; 15] = [
+        Command::ApiVersion,
+        Command::FcVariant,
+        Command::FcVersion,
+        Command::BoardInfo,
+        Command::BuildInfo,
+        Command::Name,
+        Command::WpGetInfo,
+        Command::Ident,
+        Command::RawGps,
+        Command::Analog,
+        Command::StatusEx,
+        Command::DebugMsg,
+        Command::InavStatus,
+        Command::Analog2,
+        Command::Misc2,
+    ];
+
+    /// The MSP command id.
+    pub fn id(self) -> u16 {
+        match self {
+            Command::ApiVersion => msp::MSG_API_VERSION,
+            Command::FcVariant => msp::MSG_FC_VARIANT,
+            Command::FcVersion => msp::MSG_FC_VERSION,
+            Command::BoardInfo => msp::MSG_BOARD_INFO,
+            Command::BuildInfo => msp::MSG_BUILD_INFO,
+            Command::Name => msp::MSG_NAME,
+            Command::WpGetInfo => msp::MSG_WP_GETINFO,
+            Command::Ident => msp::MSG_IDENT,
+            Command::RawGps => msp::MSG_RAW_GPS,
+            Command::Analog => msp::MSG_ANALOG,
+            Command::StatusEx => msp::MSG_STATUS_EX,
+            Command::DebugMsg => msp::MSG_DEBUGMSG,
+            Command::InavStatus => msp::MSG_INAV_STATUS,
+            Command::Analog2 => msp::MSG_ANALOG2,
+            Command::Misc2 => msp::MSG_MISC2,
+        }
+    }
+
+    /// The command for an MSP command id, if known.
+    pub fn from_id(id: u16) -> Option<Command> {
+        Command::ALL.iter().copied().find(|c| c.id() == id)
+    }
+
+    /// The command name, e.g. `"MSG_IDENT"`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Command::ApiVersion => "MSG_API_VERSION",
+            Command::FcVariant => "MSG_FC_VARIANT",
+            Command::FcVersion => "MSG_FC_VERSION",
+            Command::BoardInfo => "MSG_BOARD_INFO",
+            Command::BuildInfo => "MSG_BUILD_INFO",
+            Command::Name => "MSG_NAME",
+            Command::WpGetInfo => "MSG_WP_GETINFO",
+            Command::Ident => "MSG_IDENT",
+            Command::RawGps => "MSG_RAW_GPS",
+            Command::Analog => "MSG_ANALOG",
+            Command::StatusEx => "MSG_STATUS_EX",
+            Command::DebugMsg => "MSG_DEBUGMSG",
+            Command::InavStatus => "MSG_INAV_STATUS",
+            Command::Analog2 => "MSG_ANALOG2",
+            Command::Misc2 => "MSG_MISC2",
+        }
+    }
+
+    /// Whether the command id requires MSPv2 framing.
+    pub fn is_v2(self) -> bool {
+        self.id() > 255
+    }
+}
+
+impl std::fmt::Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Error returned when a payload cannot be decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The payload for `cmd` is `got` bytes; at least `need` are required.
+    TooShort { cmd: u16, need: usize, got: usize },
+    /// The command id is not in the catalogue.
+    Unknown(u16),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::TooShort { cmd, need, got } => {
+                write!(f, "short payload for {}: {} of {} bytes", cmd, got, need)
+            }
+            DecodeError::Unknown(cmd) => write!(f, "unknown command {}", cmd),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// A message payload with a fixed command.
+pub trait Payload: Sized {
+    const CMD: Command;
+    /// Decodes a response payload.
+    fn decode(data: &[u8]) -> Result<Self, DecodeError>;
+    /// Encodes the response payload.
+    fn encode(&self) -> Vec<u8>;
+}
+
+fn need(cmd: Command, data: &[u8], n: usize) -> Result<(), DecodeError> {
+    if data.len() < n {
+        Err(DecodeError::TooShort {
+            cmd: cmd.id(),
+            need: n,
+            got: data.len(),
+        })
+    } else {
+        Ok(())
+    }
+}
+
+fn u16_at(d: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes([d[i], d[i + 1]])
+}
+
+fn u32_at(d: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes([d[i], d[i + 1], d[i + 2], d[i + 3]])
+}
+
+fn text(d: &[u8]) -> String {
+    String::from_utf8_lossy(d)
+        .trim_end_matches('\0')
+        .to_string()
+}
+
+// Fixed width (NUL padded) string field.
+fn fixed(s: &str, n: usize) -> Vec<u8> {
+    let mut v = s.as_bytes().to_vec();
+    v.resize(n, 0);
+    v
+}
+
+/// `MSG_IDENT` (MultiWii).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Ident {
+    pub version: u8,
+    pub multitype: u8,
+    pub msp_version: u8,
+    pub capability: u32,
+}
+
+impl Payload for Ident {
+    const CMD: Command = Command::Ident;
+
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        need(Self::CMD, data, 7)?;
+        Ok(Ident {
+            version: data[0],
+            multitype: data[1],
+            msp_version: data[2],
+            capability: u32_at(data, 3),
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut v = vec![self.version, self.multitype, self.msp_version];
+        v.extend_from_slice(&self.capability.to_le_bytes());
+        v
+    }
+}
+
+/// `MSG_NAME`, the craft name.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Name {
+    pub name: String,
+}
+
+impl Payload for Name {
+    const CMD: Command = Command::Name;
+
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        Ok(Name { name: text(data) })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        self.name.as_bytes().to_vec()
+    }
+}
+
+/// `MSG_API_VERSION`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ApiVersion {
+    pub protocol: u8,
+    pub major: u8,
+    pub minor: u8,
+}
+
+impl Payload for ApiVersion {
+    const CMD: Command = Command::ApiVersion;
+
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        need(Self::CMD, data, 3)?;
+        Ok(ApiVersion {
+            protocol: data[0],
+            major: data[1],
+            minor: data[2],
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        vec![self.protocol, self.major, self.minor]
+    }
+}
+
+/// `MSG_FC_VARIANT`, the firmware identifier (e.g. `INAV`).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FcVariant {
+    pub variant: String,
+}
+
+impl Payload for FcVariant {
+    const CMD: Command = Command::FcVariant;
+
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        need(Self::CMD, data, 4)?;
+        Ok(FcVariant {
+            variant: text(&data[0..4]),
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        fixed(&self.variant, 4)
+    }
+}
+
+/// `MSG_FC_VERSION`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FcVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+impl Payload for FcVersion {
+    const CMD: Command = Command::FcVersion;
+
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        need(Self::CMD, data, 3)?;
+        Ok(FcVersion {
+            major: data[0],
+            minor: data[1],
+            patch: data[2],
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        vec![self.major, self.minor, self.patch]
+    }
+}
+
+/// `MSG_BOARD_INFO`. Older firmware only sends the 4 character board id;
+/// absent fields decode as zero / empty.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BoardInfo {
+    pub board_id: String,
+    pub hardware_revision: u16,
+    pub osd_support: u8,
+    pub comm_capabilities: u8,
+    pub target_name: String,
+}
+
+impl Payload for BoardInfo {
+    const CMD: Command = Command::BoardInfo;
+
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        need(Self::CMD, data, 4)?;
+        let mut b = BoardInfo {
+            board_id: text(&data[0..4]),
+            ..Default::default()
+        };
+        if data.len() > 5 {
+            b.hardware_revision = u16_at(data, 4);
+        }
+        if data.len() > 8 {
+            // The target name is preceded by its length.
+            let end = 9 + data[8] as usize;
+            need(Self::CMD, data, end)?;
+            b.osd_support = data[6];
+            b.comm_capabilities = data[7];
+            b.target_name = text(&data[9..end]);
+        }
+        Ok(b)
+    }
+
+    /// The target name is truncated to 255 bytes.
+    fn encode(&self) -> Vec<u8> {
+        let name = &self.target_name.as_bytes()[..self.target_name.len().min(255)];
+        let mut v = fixed(&self.board_id, 4);
+        v.extend_from_slice(&self.hardware_revision.to_le_bytes());
+        v.push(self.osd_support);
+        v.push(self.comm_capabilities);
+        v.push(name.len() as u8);
+        v.extend_from_slice(name);
+        v
+    }
+}
+
+/// `MSG_BUILD_INFO`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BuildInfo {
+    /// Build date, e.g. `Dec 29 2022`.
+    pub date: String,
+    /// Build time, e.g. `12:38:03`.
+    pub time: String,
+    pub git_revision: String,
+}
+
+impl Payload for BuildInfo {
+    const CMD: Command = Command::BuildInfo;
+
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        need(Self::CMD, data, 19)?;
+        Ok(BuildInfo {
+            date: text(&data[0..11]),
+            time: text(&data[11..19]),
+            git_revision: text(&data[19..]),
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut v = fixed(&self.date, 11);
+        v.extend(fixed(&self.time, 8));
+        v.extend_from_slice(self.git_revision.as_bytes());
+        v
+    }
+}
+
+/// `MSG_WP_GETINFO`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WpInfo {
+    pub capabilities: u8,
+    pub max_waypoints: u8,
+    pub valid: bool,
+    pub count: u8,
+}
+
+impl Payload for WpInfo {
+    const CMD: Command = Command::WpGetInfo;
+
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        need(Self::CMD, data, 4)?;
+        Ok(WpInfo {
+            capabilities: data[0],
+            max_waypoints: data[1],
+            valid: data[2] == 1,
+            count: data[3],
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        vec![
+            self.capabilities,
+            self.max_waypoints,
+            self.valid as u8,
+            self.count,
+        ]
+    }
+}
+
+/// `MSG_RAW_GPS`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RawGps {
+    pub fix: u8,
+    pub num_sat: u8,
+    /// Latitude, degrees.
+    pub lat: f64,
+    /// Longitude, degrees.
+    pub lon: f64,
+    /// Altitude, metres.
+    pub alt: i16,
+    /// Ground speed, m/s.
+    pub speed: f32,
+    /// Course over ground, degrees.
+    pub course: f32,
+    /// Not sent by MultiWii.
+    pub hdop: Option<f32>,
+}
+
+impl Payload for RawGps {
+    const CMD: Command = Command::RawGps;
+
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        need(Self::CMD, data, 16)?;
+        Ok(RawGps {
+            fix: data[0],
+            num_sat: data[1],
+            lat: u32_at(data, 2) as i32 as f64 / 1e7,
+            lon: u32_at(data, 6) as i32 as f64 / 1e7,
+            alt: u16_at(data, 10) as i16,
+            speed: u16_at(data, 12) as f32 / 100.0,
+            course: u16_at(data, 14) as f32 / 10.0,
+            hdop: if data.len() > 17 {
+                Some(u16_at(data, 16) as f32 / 100.0)
+            } else {
+                None
+            },
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut v = vec![self.fix, self.num_sat];
+        v.extend_from_slice(&((self.lat * 1e7).round() as i32).to_le_bytes());
+        v.extend_from_slice(&((self.lon * 1e7).round() as i32).to_le_bytes());
+        v.extend_from_slice(&self.alt.to_le_bytes());
+        v.extend_from_slice(&((self.speed * 100.0).round() as u16).to_le_bytes());
+        v.extend_from_slice(&((self.course * 10.0).round() as u16).to_le_bytes());
+        if let Some(hdop) = self.hdop {
+            v.extend_from_slice(&((hdop * 100.0).round() as u16).to_le_bytes());
+        }
+        v
+    }
+}
+
+/// `MSG_ANALOG` (MSPv1).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Analog {
+    /// Battery voltage, volts (0.1V resolution).
+    pub volts: f32,
+    pub mah_drawn: u16,
+    pub rssi: u16,
+    /// Current, amps.
+    pub amps: f32,
+}
+
+impl Payload for Analog {
+    const CMD: Command = Command::Analog;
+
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        need(Self::CMD, data, 7)?;
+        Ok(Analog {
+            volts: data[0] as f32 / 10.0,
+            mah_drawn: u16_at(data, 1),
+            rssi: u16_at(data, 3),
+            amps: u16_at(data, 5) as f32 / 100.0,
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut v = vec![(self.volts * 10.0).round() as u8];
+        v.extend_from_slice(&self.mah_drawn.to_le_bytes());
+        v.extend_from_slice(&self.rssi.to_le_bytes());
+        v.extend_from_slice(&((self.amps * 100.0).round() as u16).to_le_bytes());
+        v
+    }
+}
+
+/// `MSG_ANALOG2` (INAV).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Analog2 {
+    pub battery_flags: u8,
+    /// Battery voltage, volts.
+    pub volts: f32,
+    /// Current, amps.
+    pub amps: f32,
+}
+
+impl Payload for Analog2 {
+    const CMD: Command = Command::Analog2;
+
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        need(Self::CMD, data, 5)?;
+        Ok(Analog2 {
+            battery_flags: data[0],
+            volts: u16_at(data, 1) as f32 / 100.0,
+            amps: u16_at(data, 3) as f32 / 100.0,
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut v = vec![self.battery_flags];
+        v.extend_from_slice(&((self.volts * 100.0).round() as u16).to_le_bytes());
+        v.extend_from_slice(&((self.amps * 100.0).round() as u16).to_le_bytes());
+        v
+    }
+}
+
+/// `MSG_INAV_STATUS`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InavStatus {
+    pub cycle_time: u16,
+    pub i2c_errors: u16,
+    pub sensor_status: u16,
+    pub cpu_load: u16,
+    pub profile: u8,
+    pub arming_flags: u32,
+}
+
+impl Payload for InavStatus {
+    const CMD: Command = Command::InavStatus;
+
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        need(Self::CMD, data, 13)?;
+        Ok(InavStatus {
+            cycle_time: u16_at(data, 0),
+            i2c_errors: u16_at(data, 2),
+            sensor_status: u16_at(data, 4),
+            cpu_load: u16_at(data, 6),
+            profile: data[8],
+            arming_flags: u32_at(data, 9),
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut v = Vec::new();
+        v.extend_from_slice(&self.cycle_time.to_le_bytes());
+        v.extend_from_slice(&self.i2c_errors.to_le_bytes());
+        v.extend_from_slice(&self.sensor_status.to_le_bytes());
+        v.extend_from_slice(&self.cpu_load.to_le_bytes());
+        v.push(self.profile);
+        v.extend_from_slice(&self.arming_flags.to_le_bytes());
+        v
+    }
+}
+
+/// `MSG_STATUS_EX`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StatusEx {
+    pub cycle_time: u16,
+    pub i2c_errors: u16,
+    pub sensor_status: u16,
+    pub box_flags: u32,
+    pub profile: u8,
+    pub cpu_load: u16,
+    pub arming_flags: u16,
+}
+
+impl Payload for StatusEx {
+    const CMD: Command = Command::StatusEx;
+
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        need(Self::CMD, data, 15)?;
+        Ok(StatusEx {
+            cycle_time: u16_at(data, 0),
+            i2c_errors: u16_at(data, 2),
+            sensor_status: u16_at(data, 4),
+            box_flags: u32_at(data, 6),
+            profile: data[10],
+            cpu_load: u16_at(data, 11),
+            arming_flags: u16_at(data, 13),
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut v = Vec::new();
+        v.extend_from_slice(&self.cycle_time.to_le_bytes());
+        v.extend_from_slice(&self.i2c_errors.to_le_bytes());
+        v.extend_from_slice(&self.sensor_status.to_le_bytes());
+        v.extend_from_slice(&self.box_flags.to_le_bytes());
+        v.push(self.profile);
+        v.extend_from_slice(&self.cpu_load.to_le_bytes());
+        v.extend_from_slice(&self.arming_flags.to_le_bytes());
+        v
+    }
+}
+
+/// `MSG_MISC2` (INAV).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Misc2 {
+    /// Seconds since boot.
+    pub uptime: u32,
+    /// Seconds armed.
+    pub flight_time: u32,
+}
+
+impl Payload for Misc2 {
+    const CMD: Command = Command::Misc2;
+
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        need(Self::CMD, data, 8)?;
+        Ok(Misc2 {
+            uptime: u32_at(data, 0),
+            flight_time: u32_at(data, 4),
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut v = self.uptime.to_le_bytes().to_vec();
+        v.extend_from_slice(&self.flight_time.to_le_bytes());
+        v
+    }
+}
+
+/// `MSG_DEBUGMSG`, a text message from the FC.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DebugMsg {
+    pub text: String,
+}
+
+impl Payload for DebugMsg {
+    const CMD: Command = Command::DebugMsg;
+
+    fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        Ok(DebugMsg { text: text(data) })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut v = self.text.as_bytes().to_vec();
+        v.push(0);
+        v
+    }
+}
+
+/// A decoded message of any known command.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Ident(Ident),
+    Name(Name),
+    ApiVersion(ApiVersion),
+    FcVariant(FcVariant),
+    FcVersion(FcVersion),
+    BoardInfo(BoardInfo),
+    BuildInfo(BuildInfo),
+    WpInfo(WpInfo),
+    RawGps(RawGps),
+    Analog(Analog),
+    Analog2(Analog2),
+    InavStatus(InavStatus),
+    StatusEx(StatusEx),
+    Misc2(Misc2),
+    DebugMsg(DebugMsg),
+}
+
+impl Message {
+    pub fn command(&self) -> Command {
+        match self {
+            Message::Ident(_) => Ident::CMD,
+            Message::Name(_) => Name::CMD,
+            Message::ApiVersion(_) => ApiVersion::CMD,
+            Message::FcVariant(_) => FcVariant::CMD,
+            Message::FcVersion(_) => FcVersion::CMD,
+            Message::BoardInfo(_) => BoardInfo::CMD,
+            Message::BuildInfo(_) => BuildInfo::CMD,
+            Message::WpInfo(_) => WpInfo::CMD,
+            Message::RawGps(_) => RawGps::CMD,
+            Message::Analog(_) => Analog::CMD,
+            Message::Analog2(_) => Analog2::CMD,
+            Message::InavStatus(_) => InavStatus::CMD,
+            Message::StatusEx(_) => StatusEx::CMD,
+            Message::Misc2(_) => Misc2::CMD,
+            Message::DebugMsg(_) => DebugMsg::CMD,
+        }
+    }
+
+    /// Encodes the response payload.
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Message::Ident(m) => m.encode(),
+            Message::Name(m) => m.encode(),
+            Message::ApiVersion(m) => m.encode(),
+            Message::FcVariant(m) => m.encode(),
+            Message::FcVersion(m) => m.encode(),
+            Message::BoardInfo(m) => m.encode(),
+            Message::BuildInfo(m) => m.encode(),
+            Message::WpInfo(m) => m.encode(),
+            Message::RawGps(m) => m.encode(),
+            Message::Analog(m) => m.encode(),
+            Message::Analog2(m) => m.encode(),
+            Message::InavStatus(m) => m.encode(),
+            Message::StatusEx(m) => m.encode(),
+            Message::Misc2(m) => m.encode(),
+            Message::DebugMsg(m) => m.encode(),
+        }
+    }
+}
+
+/// Decodes the response payload `data` for command id `cmd`.
+pub fn decode(cmd: u16, data: &[u8]) -> Result<Message, DecodeError> {
+    let c = Command::from_id(cmd).ok_or(DecodeError::Unknown(cmd))?;
+    Ok(match c {
+        Command::Ident => Message::Ident(Ident::decode(data)?),
+        Command::Name => Message::Name(Name::decode(data)?),
+        Command::ApiVersion => Message::ApiVersion(ApiVersion::decode(data)?),
+        Command::FcVariant => Message::FcVariant(FcVariant::decode(data)?),
+        Command::FcVersion => Message::FcVersion(FcVersion::decode(data)?),
+        Command::BoardInfo => Message::BoardInfo(BoardInfo::decode(data)?),
+        Command::BuildInfo => Message::BuildInfo(BuildInfo::decode(data)?),
+        Command::WpGetInfo => Message::WpInfo(WpInfo::decode(data)?),
+        Command::RawGps => Message::RawGps(RawGps::decode(data)?),
+        Command::Analog => Message::Analog(Analog::decode(data)?),
+        Command::Analog2 => Message::Analog2(Analog2::decode(data)?),
+        Command::InavStatus => Message::InavStatus(InavStatus::decode(data)?),
+        Command::StatusEx => Message::StatusEx(StatusEx::decode(data)?),
+        Command::Misc2 => Message::Misc2(Misc2::decode(data)?),
+        Command::DebugMsg => Message::DebugMsg(DebugMsg::decode(data)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Minimum payload length of each command.
+    fn min_len(c: Command) -> usize {
+        match c {
+            Command::Name | Command::DebugMsg => 0,
+            Command::ApiVersion | Command::FcVersion => 3,
+            Command::FcVariant | Command::BoardInfo | Command::WpGetInfo => 4,
+            Command::Analog2 => 5,
+            Command::Ident | Command::Analog => 7,
+            Command::Misc2 => 8,
+            Command::InavStatus => 13,
+            Command::StatusEx => 15,
+            Command::RawGps => 16,
+            Command::BuildInfo => 19,
+        }
+    }
+
+    #[test]
+    fn truncated_payloads() {
+        // Zero length strings, so longer payloads are valid.
+        let data = [0u8; 64];
+        for c in Command::ALL {
+            let need = min_len(c);
+            for got in 0..need {
+                assert_eq!(
+                    decode(c.id(), &data[..got]),
+                    Err(DecodeError::TooShort {
+                        cmd: c.id(),
+                        need,
+                        got
+                    }),
+                    "{}",
+                    c
+                );
+            }
+            // Longer payloads (including optional fields) decode.
+            for n in need..data.len() {
+                let m = decode(c.id(), &data[..n]).unwrap_or_else(|e| panic!("{}: {}", c, e));
+                assert_eq!(m.command(), c);
+            }
+        }
+    }
+
+    #[test]
+    fn board_info_target_name() {
+        let mut b = BoardInfo {
+            board_id: "MF4S".to_string(),
+            hardware_revision: 2,
+            osd_support: 2,
+            comm_capabilities: 1,
+            target_name: "MATEKF405".to_string(),
+        };
+        let mut data = b.encode();
+        assert_eq!(data[8], 9);
+        // Fields after the name are ignored.
+        data.extend_from_slice(b"\x03\x00");
+        assert_eq!(BoardInfo::decode(&data), Ok(b.clone()));
+        assert_eq!(
+            BoardInfo::decode(&data[..12]),
+            Err(DecodeError::TooShort {
+                cmd: msp::MSG_BOARD_INFO,
+                need: 18,
+                got: 12
+            })
+        );
+
+        b.target_name = "X".repeat(300);
+        let data = b.encode();
+        assert_eq!((data[8], data.len()), (255, 9 + 255));
+        assert_eq!(
+            BoardInfo::decode(&data).unwrap().target_name,
+            "X".repeat(255)
+        );
+    }
+
+    #[test]
+    fn unknown_command() {
+        assert_eq!(decode(0x4242, &[]), Err(DecodeError::Unknown(0x4242)));
+    }
+}
@@ -1,3 +1,5 @@
+//! MSP (MultiWii Serial Protocol) v1 / v2 message encoding and decoding.
+
 pub const MSG_IDENT: u16 = 100;
 pub const MSG_NAME: u16 = 10;
 pub const MSG_API_VERSION: u16 = 1;
@@ -14,25 +16,41 @@ pub const MSG_ANALOG2: u16 = 0x2002;
 pub const MSG_INAV_STATUS: u16 = 0x2000;
 pub const MSG_MISC2: u16 = 0x203a;
 
-#[derive(Debug, Clone)]
+/// MSPv1 command id for an encapsulated MSPv2 frame.
+pub const MSG_V2_FRAME: u16 = 255;
+
+/// MSPv1 length byte indicating a jumbo frame (16 bit length follows the command).
+const JUMBO_FRAME_SIZE: usize = 255;
+
+/// Outcome of decoding a received frame.
+#[derive(Debug, Default, Clone)]
 pub enum MSPRes {
+    /// Valid response (`>`) from the FC.
     Ok,
+    /// Checksum mismatch.
     Crc,
-    Dirn,
+    /// Error response (`!`); the FC does not support the command `cmd`.
+    Error,
+    /// Request frame (`<`), e.g. seen on a shared or loopback link.
+    Request,
+    /// Transport failure / end of stream; no frame data.
+    #[default]
     Fail,
 }
 
-impl Default for MSPRes {
-    fn default() -> Self {
-        MSPRes::Fail
-    }
-}
-
+/// A decoded MSP frame (either protocol version).
 #[derive(Debug, Default, Clone)]
 pub struct MSPMsg {
     pub len: u16,
     pub cmd: u16,
     pub ok: MSPRes,
+    /// Direction character from the header (`<`, `>` or `!`).
+    pub dirn: u8,
+    /// Protocol version of the frame (1 or 2); 2 for an MSPv2 frame
+    /// tunnelled in MSPv1.
+    pub vers: u8,
+    /// The MSPv2 frame was tunnelled in MSPv1 (command [`MSG_V2_FRAME`]).
+    pub tunnelled: bool,
     pub data: Vec<u8>,
 }
 
@@ -48,50 +66,129 @@ fn crc8_dvb_s2(mut c: u8, a: u8) -> u8 {
     c
 }
 
-pub fn encode_msp2(cmd: u16, payload: &[u8]) -> Vec<u8> {
-    let paylen = payload.len();
-    let mut v = vec![0; paylen + 9];
-    v[0] = b'$';
-    v[1] = b'X';
-    v[2] = b'<';
-    v[3] = 0;
-    v[4] = (cmd & 0xff) as u8;
-    v[5] = (cmd >> 8) as u8;
-    v[6] = (paylen & 0xff) as u8;
-    v[7] = (paylen >> 8) as u8;
-    v[8..paylen + 8].copy_from_slice(payload);
-    let mut crc: u8 = 0;
-    for e in v.iter().take(paylen + 8).skip(3) {
-        crc = crc8_dvb_s2(crc, *e);
-    }
-    v[paylen + 8] = crc;
-    v
-}
-
-pub fn encode_msp(cmd: u16, payload: &[u8]) -> Vec<u8> {
+/// Error returned when a frame cannot be encoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncodeError {
+    /// The payload (length given) is too large for the frame format.
+    PayloadTooLarge(usize),
+    /// The command id does not fit in an MSPv1 frame.
+    CommandOutOfRange(u16),
+}
+
+impl std::fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncodeError::PayloadTooLarge(n) => write!(f, "payload too large ({} bytes)", n),
+            EncodeError::CommandOutOfRange(c) => write!(f, "command {} out of range for MSPv1", c),
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+fn v2_body(cmd: u16, payload: &[u8]) -> Result<Vec<u8>, EncodeError> {
     let paylen = payload.len();
-    //    let mut v: Vec<u8> = Vec::new();
+    if paylen > u16::MAX as usize {
+        return Err(EncodeError::PayloadTooLarge(paylen));
+    }
     let mut v = vec![0; paylen + 6];
-    v[0] = b'$';
-    v[1] = b'M';
-    v[2] = b'<';
-    v[3] = paylen as u8;
-    v[4] = cmd as u8;
+    v[0] = 0;
+    v[1] = (cmd & 0xff) as u8;
+    v[2] = (cmd >> 8) as u8;
+    v[3] = (paylen & 0xff) as u8;
+    v[4] = (paylen >> 8) as u8;
     v[5..paylen + 5].copy_from_slice(payload);
-    let mut crc: u8 = v[3] ^ v[4];
-    for c in payload {
-        crc ^= c;
+    v[paylen + 5] = v.iter().take(paylen + 5).fold(0, |c, e| crc8_dvb_s2(c, *e));
+    Ok(v)
+}
+
+fn encode_v1(dirn: u8, cmd: u8, payload: &[u8]) -> Result<Vec<u8>, EncodeError> {
+    let paylen = payload.len();
+    if paylen > u16::MAX as usize {
+        return Err(EncodeError::PayloadTooLarge(paylen));
+    }
+    let mut v = Vec::with_capacity(paylen + 9);
+    v.extend_from_slice(&[b'$', b'M', dirn]);
+    if paylen >= JUMBO_FRAME_SIZE {
+        v.push(JUMBO_FRAME_SIZE as u8);
+        v.push(cmd);
+        v.extend_from_slice(&(paylen as u16).to_le_bytes());
+    } else {
+        v.push(paylen as u8);
+        v.push(cmd);
+    }
+    v.extend_from_slice(payload);
+    let crc = v.iter().skip(3).fold(0, |c, e| c ^ e);
+    v.push(crc);
+    Ok(v)
+}
+
+/// Encodes an MSPv2 request frame (`$X<`) for `cmd` with `payload`.
+pub fn encode_msp2(cmd: u16, payload: &[u8]) -> Result<Vec<u8>, EncodeError> {
+    let mut v = b"$X<".to_vec();
+    v.extend(v2_body(cmd, payload)?);
+    Ok(v)
+}
+
+/// Encodes an MSPv1 request frame (`$M<`) for `cmd` with `payload`.
+///
+/// Payloads of 255 bytes or more are sent as a "jumbo" frame (length byte
+/// 255 followed by a 16 bit length).
+pub fn encode_msp(cmd: u16, payload: &[u8]) -> Result<Vec<u8>, EncodeError> {
+    if cmd > 255 {
+        return Err(EncodeError::CommandOutOfRange(cmd));
     }
-    v[paylen + 5] = crc;
-    v
+    encode_v1(b'<', cmd as u8, payload)
+}
+
+/// Encodes an MSPv2 request for `cmd` tunnelled in an MSPv1 frame
+/// (command [`MSG_V2_FRAME`]), for links that only pass `$M` frames.
+pub fn encode_msp2_over_v1(cmd: u16, payload: &[u8]) -> Result<Vec<u8>, EncodeError> {
+    encode_v1(b'<', MSG_V2_FRAME as u8, &v2_body(cmd, payload)?)
 }
 
+/// Encodes an MSPv2 response (`>`, or `!` if `error`) for `cmd` tunnelled
+/// in an MSPv1 frame, as a flight controller answers a tunnelled request.
+pub fn encode_response_over_v1(
+    cmd: u16,
+    payload: &[u8],
+    error: bool,
+) -> Result<Vec<u8>, EncodeError> {
+    let dirn = if error { b'!' } else { b'>' };
+    encode_v1(dirn, MSG_V2_FRAME as u8, &v2_body(cmd, payload)?)
+}
+
+/// Encodes a response frame, as sent by a flight controller, in MSP version
+/// `vers` (1 or 2): `>`, or `!` if `error` (the command is unsupported).
+pub fn encode_response(
+    vers: u8,
+    cmd: u16,
+    payload: &[u8],
+    error: bool,
+) -> Result<Vec<u8>, EncodeError> {
+    let dirn = if error { b'!' } else { b'>' };
+    if vers == 1 {
+        if cmd > 255 {
+            return Err(EncodeError::CommandOutOfRange(cmd));
+        }
+        encode_v1(dirn, cmd as u8, payload)
+    } else {
+        let mut v = vec![b'$', b'X', dirn];
+        v.extend(v2_body(cmd, payload)?);
+        Ok(v)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
 enum States {
+    #[default]
     Init,
     M,
     Dirn,
     Len,
     Cmd,
+    JLen1,
+    JLen2,
     Data,
     Crc,
 
@@ -105,144 +202,475 @@ enum States {
     XChecksum,
 }
 
-pub fn reader<T>(mut sd: T, tx: crossbeam::channel::Sender<MSPMsg>) where T: std::io::Read + Unpin,  {
-    let mut msg = MSPMsg::default();
-    let mut n = States::Init;
-    let mut crc = 0u8;
-    let mut count = 0u16;
-    let mut dirnok = false;
+/// Incremental (sans-IO) MSP v1 / v2 frame parser.
+///
+/// Bytes may be supplied in arbitrary chunks as they arrive from any
+/// transport; each completed frame is returned as an [`MSPMsg`] with `ok` set
+/// to the decode result.
+///
+/// ```
+/// use msptest::msp::{self, MspParser, MSPRes};
+///
+/// let mut parser = MspParser::new();
+/// let buf = [b'$', b'M', b'>', 1, 100, 42, 1 ^ 100 ^ 42];
+/// let frames: Vec<_> = parser.feed(&buf).collect();
+/// assert_eq!(frames.len(), 1);
+/// assert_eq!(frames[0].cmd, msp::MSG_IDENT);
+/// assert!(matches!(frames[0].ok, MSPRes::Ok));
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct MspParser {
+    state: States,
+    msg: MSPMsg,
+    crc: u8,
+    count: u16,
+}
+
+impl MspParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Discards any partially decoded frame.
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// True between frames, i.e. the next byte is not part of a frame unless
+    /// it is a `$` starting one.
+    pub fn is_idle(&self) -> bool {
+        matches!(self.state, States::Init)
+    }
+
+    /// True just after the `$` that starts a frame.
+    pub fn is_frame_start(&self) -> bool {
+        matches!(self.state, States::M)
+    }
+
+    /// Processes `buf`, returning an iterator over the frames it completes.
+    ///
+    /// Bytes are consumed as the iterator is advanced; if it is dropped before
+    /// it is exhausted, the remaining bytes are not processed.
+    pub fn feed<'a>(&'a mut self, buf: &'a [u8]) -> Frames<'a> {
+        Frames {
+            parser: self,
+            buf: buf.iter(),
+        }
+    }
+
+    /// Processes a single byte, returning the frame if it completes one.
+    pub fn push(&mut self, e: u8) -> Option<MSPMsg> {
+        match self.state {
+            States::Init => {
+                if e == b'$' {
+                    self.state = States::M;
+                    self.msg = MSPMsg::default();
+                }
+            }
+            States::M => {
+                self.state = match e {
+                    b'M' => States::Dirn,
+                    b'X' => States::XHeader2,
+                    // Restart on a repeated `$`, e.g. `$$M>`.
+                    b'$' => States::M,
+                    _ => States::Init,
+                };
+                self.msg.vers = if e == b'X' { 2 } else { 1 };
+            }
+            States::Dirn => match e {
+                b'!' | b'>' | b'<' => {
+                    self.state = States::Len;
+                    self.msg.dirn = e;
+                }
+                b'$' => self.state = States::M,
+                _ => self.state = States::Init,
+            },
+            States::XHeader2 => match e {
+                b'!' | b'>' | b'<' => {
+                    self.state = States::XFlags;
+                    self.msg.dirn = e;
+                }
+                b'$' => self.state = States::M,
+                _ => self.state = States::Init,
+            },
+            States::XFlags => {
+                self.crc = crc8_dvb_s2(0, e);
+                self.state = States::XId1;
+            }
+            States::XId1 => {
+                self.crc = crc8_dvb_s2(self.crc, e);
+                self.msg.cmd = e as u16;
+                self.state = States::XId2;
+            }
+            States::XId2 => {
+                self.crc = crc8_dvb_s2(self.crc, e);
+                self.msg.cmd |= (e as u16) << 8;
+                self.state = States::XLen1;
+            }
+            States::XLen1 => {
+                self.crc = crc8_dvb_s2(self.crc, e);
+                self.msg.len = e as u16;
+                self.state = States::XLen2;
+            }
+            States::XLen2 => {
+                self.crc = crc8_dvb_s2(self.crc, e);
+                self.msg.len |= (e as u16) << 8;
+                if self.msg.len > 0 {
+                    self.state = States::XData;
+                    self.count = 0;
+                    self.msg.data = vec![0; self.msg.len.into()];
+                } else {
+                    self.state = States::XChecksum;
+                }
+            }
+            States::XData => {
+                self.crc = crc8_dvb_s2(self.crc, e);
+                self.msg.data[self.count as usize] = e;
+                self.count += 1;
+                if self.count == self.msg.len {
+                    self.state = States::XChecksum;
+                }
+            }
+            States::XChecksum => return Some(self.complete(e)),
+            States::Len => {
+                self.msg.len = e as u16;
+                self.crc = e;
+                self.state = States::Cmd;
+            }
+            States::Cmd => {
+                self.msg.cmd = e as u16;
+                self.crc ^= e;
+                if self.msg.len as usize == JUMBO_FRAME_SIZE {
+                    self.state = States::JLen1;
+                } else {
+                    self.v1_data();
+                }
+            }
+            States::JLen1 => {
+                self.crc ^= e;
+                self.msg.len = e as u16;
+                self.state = States::JLen2;
+            }
+            States::JLen2 => {
+                self.crc ^= e;
+                self.msg.len |= (e as u16) << 8;
+                self.v1_data();
+            }
+            States::Data => {
+                self.msg.data[self.count as usize] = e;
+                self.crc ^= e;
+                self.count += 1;
+                if self.count == self.msg.len {
+                    self.state = States::Crc;
+                }
+            }
+            States::Crc => return Some(self.complete(e)),
+        }
+        None
+    }
+
+    fn v1_data(&mut self) {
+        if self.msg.len == 0 {
+            self.state = States::Crc;
+        } else {
+            self.msg.data = vec![0; self.msg.len.into()];
+            self.state = States::Data;
+            self.count = 0;
+        }
+    }
+
+    fn complete(&mut self, crc: u8) -> MSPMsg {
+        let v1 = matches!(self.state, States::Crc);
+        self.state = States::Init;
+        self.msg.ok = if self.crc != crc {
+            MSPRes::Crc
+        } else {
+            match self.msg.dirn {
+                b'>' => MSPRes::Ok,
+                b'!' => MSPRes::Error,
+                _ => MSPRes::Request,
+            }
+        };
+        if v1 && self.msg.cmd == MSG_V2_FRAME && !matches!(self.msg.ok, MSPRes::Crc) {
+            self.unwrap_v2();
+        }
+        std::mem::take(&mut self.msg)
+    }
+
+    // Replaces an MSPv2-over-v1 frame with the encapsulated v2 frame.
+    fn unwrap_v2(&mut self) {
+        let d = &self.msg.data;
+        if d.len() < 6 {
+            self.msg.ok = MSPRes::Crc;
+            return;
+        }
+        let cmd = u16::from_le_bytes([d[1], d[2]]);
+        let len = u16::from_le_bytes([d[3], d[4]]);
+        let n = len as usize + 5;
+        if d.len() != n + 1 || d.iter().take(n).fold(0, |c, e| crc8_dvb_s2(c, *e)) != d[n] {
+            self.msg.ok = MSPRes::Crc;
+            return;
+        }
+        self.msg.data = d[5..n].to_vec();
+        self.msg.cmd = cmd;
+        self.msg.len = len;
+        self.msg.vers = 2;
+        self.msg.tunnelled = true;
+    }
+}
+
+/// Iterator over the frames completed by [`MspParser::feed`].
+pub struct Frames<'a> {
+    parser: &'a mut MspParser,
+    buf: std::slice::Iter<'a, u8>,
+}
+
+impl Iterator for Frames<'_> {
+    type Item = MSPMsg;
+
+    fn next(&mut self) -> Option<MSPMsg> {
+        for e in self.buf.by_ref() {
+            if let Some(msg) = self.parser.push(*e) {
+                return Some(msg);
+            }
+        }
+        None
+    }
+}
+
+/// Reads MSP frames from `sd` and sends each one on `tx`.
+///
+/// Runs until the reader returns EOF or an error, at which point a default
+/// message (`MSPRes::Fail`) is sent and the function returns. Read timeouts
+/// (`io::ErrorKind::TimedOut`) are ignored.
+pub fn reader<T>(mut sd: T, tx: crossbeam::channel::Sender<MSPMsg>)
+where
+    T: std::io::Read,
+{
+    let mut parser = MspParser::new();
     let mut inp = [0u8; 256];
     loop {
         match sd.read(&mut inp) {
-            Ok(nbytes) => {
-		if nbytes == 0 {
-                    tx.send(MSPMsg::default()).unwrap();
-                    return
-		} else {
-                    for e in inp.iter().take(nbytes) {
-			match n {
-                            States::Init => {
-				if *e == b'$' {
-                                    n = States::M;
-                                    msg.ok = MSPRes::Fail;
-                                    msg.len = 0;
-                                    msg.cmd = 0;
-                                    dirnok = false;
-				}
-                            }
-                            States::M => {
-				n = match *e {
-                                    b'M' => States::Dirn,
-                                    b'X' => States::XHeader2,
-                                    _ => States::Init,
-				}
-                            }
-                            States::Dirn => match *e {
-				b'!' => {
-                                    n = States::Len;
-				}
-				b'>' => {
-                                    n = States::Len;
-                                    dirnok = true;
-				}
-				_ => n = States::Init,
-                            },
-                            States::XHeader2 => match *e {
-				b'!' => n = States::XFlags,
-				b'>' => {
-                                    n = States::XFlags;
-                                    dirnok = true;
-				}
-				_ => n = States::Init,
-                            },
-                            States::XFlags => {
-				crc = crc8_dvb_s2(0, *e);
-				n = States::XId1;
-                            }
-                            States::XId1 => {
-				crc = crc8_dvb_s2(crc, *e);
-				msg.cmd = *e as u16;
-				n = States::XId2;
-                            }
-                            States::XId2 => {
-				crc = crc8_dvb_s2(crc, *e);
-				msg.cmd |= (*e as u16) << 8;
-				n = States::XLen1;
-                            }
-                            States::XLen1 => {
-				crc = crc8_dvb_s2(crc, *e);
-				msg.len = *e as u16;
-				n = States::XLen2;
-                            }
-                            States::XLen2 => {
-				crc = crc8_dvb_s2(crc, *e);
-				msg.len |= (*e as u16) << 8;
-				if msg.len > 0 {
-                                    n = States::XData;
-                                    count = 0;
-                                    msg.data = vec![0; msg.len.into()];
-				} else {
-                                    n = States::XChecksum;
-				}
-                            }
-                            States::XData => {
-				crc = crc8_dvb_s2(crc, *e);
-				msg.data[count as usize] = *e;
-				count += 1;
-				if count == msg.len {
-                                    n = States::XChecksum;
-				}
-                            }
-                            States::XChecksum => {
-				if crc != *e {
-                                    msg.ok = MSPRes::Crc
-				} else {
-                                    msg.ok = if dirnok { MSPRes::Ok } else { MSPRes::Dirn };
-				}
-				tx.send(msg.clone()).unwrap();
-				n = States::Init;
-                            }
-                            States::Len => {
-				msg.len = *e as u16;
-				crc = *e;
-				n = States::Cmd;
-                            }
-                            States::Cmd => {
-				msg.cmd = *e as u16;
-				crc ^= *e;
-				if msg.len == 0 {
-                                    n = States::Crc;
-				} else {
-                                    msg.data = vec![0; msg.len.into()];
-                                    n = States::Data;
-                                    count = 0;
-				}
-                            }
-                            States::Data => {
-				msg.data[count as usize] = *e;
-				crc ^= *e;
-				count += 1;
-				if count == msg.len {
-                                    n = States::Crc;
-				}
-                            }
-                            States::Crc => {
-				if crc != *e {
-                                    msg.ok = MSPRes::Crc;
-				} else {
-                                    msg.ok = if dirnok { MSPRes::Ok } else { MSPRes::Dirn };
-				}
-				tx.send(msg.clone()).unwrap();
-				n = States::Init;
-                            }
-			}
+            Ok(nbytes) if nbytes > 0 => {
+                for msg in parser.feed(&inp[..nbytes]) {
+                    if tx.send(msg).is_err() {
+                        return;
                     }
-		}
-	    },
-            Err(_) => {
-                tx.send(MSPMsg::default()).unwrap();
-                return
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => (),
+            _ => {
+                _ = tx.send(MSPMsg::default());
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(buf: &[u8]) -> Vec<MSPMsg> {
+        MspParser::new().feed(buf).collect()
+    }
+
+    // MSG_IDENT response from MultiWii 2.4.
+    const IDENT: [u8; 13] = *b"$M>\x07\x64\xf0\x03\x00\x00\x00\x00\x00\x90";
+
+    #[test]
+    fn split_frame() {
+        let mut p = MspParser::new();
+        for chunk in [&IDENT[..1], &IDENT[1..4], &IDENT[4..5], &IDENT[5..12]] {
+            assert_eq!(p.feed(chunk).count(), 0);
+            assert!(!p.is_idle());
+        }
+        let m: Vec<_> = p.feed(&IDENT[12..]).collect();
+        assert_eq!(m.len(), 1);
+        assert!(matches!(m[0].ok, MSPRes::Ok));
+        assert_eq!((m[0].cmd, m[0].len, m[0].vers), (MSG_IDENT, 7, 1));
+        assert_eq!(m[0].data, &IDENT[5..12]);
+        assert!(p.is_idle());
+    }
+
+    #[test]
+    fn crc_error() {
+        let mut buf = IDENT;
+        buf[12] ^= 0xff;
+        let m = parse(&buf);
+        assert_eq!(m.len(), 1);
+        assert!(matches!(m[0].ok, MSPRes::Crc));
+        assert_eq!(m[0].cmd, MSG_IDENT);
+
+        let mut buf = encode_response(2, MSG_ANALOG2, &[1, 2, 3, 4, 5], false).unwrap();
+        buf[9] ^= 1;
+        let m = parse(&buf);
+        assert_eq!(m.len(), 1);
+        assert!(matches!(m[0].ok, MSPRes::Crc));
+        assert_eq!(m[0].cmd, MSG_ANALOG2);
+    }
+
+    #[test]
+    fn resync_after_garbage() {
+        let mut buf = b"\x00junk$$M$X\xff$M?".to_vec();
+        buf.extend_from_slice(&IDENT);
+        let m = parse(&buf);
+        assert_eq!(m.len(), 1);
+        assert!(matches!(m[0].ok, MSPRes::Ok));
+        assert_eq!(m[0].cmd, MSG_IDENT);
+
+        // A `$` in the header restarts it.
+        for prefix in [&b"$"[..], b"$$", b"$M", b"$X", b"$M$", b"$X$"] {
+            let mut buf = prefix.to_vec();
+            buf.extend_from_slice(&IDENT);
+            buf.extend(encode_msp2(MSG_MISC2, &[]).unwrap());
+            let m = parse(&buf);
+            let cmds: Vec<_> = m.iter().map(|m| m.cmd).collect();
+            assert_eq!(cmds, [MSG_IDENT, MSG_MISC2], "{:?}", prefix);
+            assert!(m.iter().all(|m| !matches!(m.ok, MSPRes::Crc)));
+        }
+    }
+
+    #[test]
+    fn zero_length_payloads() {
+        for buf in [
+            encode_msp(MSG_NAME, &[]).unwrap(),
+            encode_msp2(MSG_MISC2, &[]).unwrap(),
+            encode_response(1, MSG_NAME, &[], true).unwrap(),
+        ] {
+            let m = parse(&buf);
+            assert_eq!(m.len(), 1, "{:?}", buf);
+            assert!(!matches!(m[0].ok, MSPRes::Crc));
+            assert_eq!(m[0].len, 0);
+            assert!(m[0].data.is_empty());
+        }
+        assert_eq!(parse(&encode_msp(MSG_NAME, &[]).unwrap())[0].dirn, b'<');
+    }
+
+    #[test]
+    fn back_to_back_frames() {
+        let mut buf = IDENT.to_vec();
+        buf.extend(encode_msp2(MSG_INAV_STATUS, &[]).unwrap());
+        buf.extend(encode_response(1, MSG_NAME, b"Benchy", false).unwrap());
+        buf.extend(encode_response(2, MSG_MISC2, &[0; 8], true).unwrap());
+        let m = parse(&buf);
+        let got: Vec<_> = m.iter().map(|m| (m.cmd, m.vers, m.dirn)).collect();
+        assert_eq!(
+            got,
+            [
+                (MSG_IDENT, 1, b'>'),
+                (MSG_INAV_STATUS, 2, b'<'),
+                (MSG_NAME, 1, b'>'),
+                (MSG_MISC2, 2, b'!'),
+            ]
+        );
+        assert!(matches!(m[1].ok, MSPRes::Request));
+        assert!(matches!(m[3].ok, MSPRes::Error));
+        assert_eq!(m[2].data, b"Benchy");
+    }
+
+    fn payload(n: usize) -> Vec<u8> {
+        (0..n).map(|i| (i * 7 + i / 256) as u8).collect()
+    }
+
+    fn round_trip(buf: &[u8]) -> MSPMsg {
+        let m = parse(buf);
+        assert_eq!(m.len(), 1);
+        assert!(!matches!(m[0].ok, MSPRes::Crc | MSPRes::Fail));
+        m.into_iter().next().unwrap()
+    }
+
+    #[test]
+    fn jumbo_round_trip() {
+        for n in [0, 1, 254, 255, 256, 65535] {
+            let data = payload(n);
+            let buf = encode_msp(MSG_NAME, &data).unwrap();
+            // Jumbo frames have length byte 255 then a 16 bit length.
+            if n >= 255 {
+                assert_eq!(buf[3..7], [255, MSG_NAME as u8, n as u8, (n >> 8) as u8]);
+                assert_eq!(buf.len(), n + 8);
+            } else {
+                assert_eq!(buf[3] as usize, n);
+                assert_eq!(buf.len(), n + 6);
             }
+            let m = round_trip(&buf);
+            assert_eq!((m.cmd, m.len as usize, m.vers), (MSG_NAME, n, 1));
+            assert_eq!(m.data, data);
+
+            let m = round_trip(&encode_response(1, MSG_DEBUGMSG, &data, false).unwrap());
+            assert!(matches!(m.ok, MSPRes::Ok));
+            assert_eq!(m.data, data);
+
+            let m = round_trip(&encode_msp2(MSG_ANALOG2, &data).unwrap());
+            assert_eq!((m.cmd, m.vers), (MSG_ANALOG2, 2));
+            assert_eq!(m.data, data);
         }
     }
+
+    #[test]
+    fn v2_over_v1_round_trip() {
+        // A 249 byte payload is a 255 byte v2 body, the first jumbo frame.
+        for (n, jumbo) in [
+            (0, false),
+            (10, false),
+            (248, false),
+            (249, true),
+            (1000, true),
+        ] {
+            let data = payload(n);
+            let buf = encode_msp2_over_v1(MSG_INAV_STATUS, &data).unwrap();
+            assert_eq!(&buf[..3], b"$M<");
+            assert_eq!(buf[3] == 255, jumbo, "{}", n);
+            assert_eq!(buf[4] as u16, MSG_V2_FRAME);
+            let m = round_trip(&buf);
+            assert!(matches!(m.ok, MSPRes::Request));
+            assert_eq!((m.cmd, m.len as usize, m.vers), (MSG_INAV_STATUS, n, 2));
+            assert!(m.tunnelled);
+            assert_eq!(m.data, data);
+
+            let buf = encode_response_over_v1(MSG_INAV_STATUS, &data, false).unwrap();
+            assert_eq!(&buf[..3], b"$M>");
+            let m = round_trip(&buf);
+            assert!(matches!(m.ok, MSPRes::Ok) && m.tunnelled);
+            assert_eq!((m.cmd, m.vers), (MSG_INAV_STATUS, 2));
+            assert_eq!(m.data, data);
+        }
+        assert!(!round_trip(&encode_msp2(MSG_INAV_STATUS, &[]).unwrap()).tunnelled);
+    }
+
+    #[test]
+    fn v2_over_v1_bad_inner_crc() {
+        let mut body = v2_body(MSG_INAV_STATUS, &[1, 2, 3]).unwrap();
+        *body.last_mut().unwrap() ^= 1;
+        let m = parse(&encode_v1(b'>', MSG_V2_FRAME as u8, &body).unwrap());
+        assert_eq!(m.len(), 1);
+        assert!(matches!(m[0].ok, MSPRes::Crc));
+    }
+
+    #[test]
+    fn encode_errors() {
+        let big = vec![0; u16::MAX as usize + 1];
+        assert_eq!(
+            encode_msp(MSG_NAME, &big),
+            Err(EncodeError::PayloadTooLarge(65536))
+        );
+        assert_eq!(
+            encode_msp2(MSG_MISC2, &big),
+            Err(EncodeError::PayloadTooLarge(65536))
+        );
+        assert_eq!(
+            encode_response(1, MSG_NAME, &big, false),
+            Err(EncodeError::PayloadTooLarge(65536))
+        );
+        // The v2 body (6 bytes more than the payload) must fit in the v1 frame.
+        assert_eq!(
+            encode_msp2_over_v1(MSG_MISC2, &big[..65530]),
+            Err(EncodeError::PayloadTooLarge(65536))
+        );
+        assert!(encode_msp2_over_v1(MSG_MISC2, &big[..65529]).is_ok());
+        assert_eq!(
+            encode_msp(MSG_MISC2, &[]),
+            Err(EncodeError::CommandOutOfRange(MSG_MISC2))
+        );
+    }
 }
@@ -0,0 +1,314 @@
+//! Network (TCP / UDP) connections.
+
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Largest UDP payload.
+pub(crate) const MAX_DATAGRAM: usize = 65536;
+
+/// Largest payload sent in one datagram (the IPv4 limit).
+const MAX_SEND: usize = 65507;
+
+/// Interval at which a blocked [`UdpStream`] read checks for shutdown.
+const UDP_POLL: Duration = Duration::from_millis(200);
+
+/// Connects to `host:port` over TCP, with Nagle disabled.
+pub fn tcp_connect(host: &str, port: u16) -> io::Result<TcpStream> {
+    let conn = TcpStream::connect((host, port))?;
+    _ = conn.set_nodelay(true);
+    Ok(conn)
+}
+
+/// Returns the unspecified address of the same family as `remote`, with
+/// port `bind` (0 for any), to bind a socket to.
+pub(crate) fn bind_addr(remote: &SocketAddr, bind: u16) -> SocketAddr {
+    match remote {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, bind)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, bind)),
+    }
+}
+
+pub(crate) fn no_address() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "host not found")
+}
+
+/// UDP socket usable with `std::io::Read` / `std::io::Write`, either
+/// connected to a peer ([`udp_connect`]) or answering whichever peer last
+/// sent to a listening socket ([`UdpServer::accept`]).
+///
+/// Each write is sent as one datagram, of at most 65507 bytes (so
+/// `write_all` splits a larger buffer). Reads return the received datagrams
+/// in order; a datagram larger than the read buffer is returned over
+/// successive reads rather than truncated.
+#[derive(Debug)]
+pub struct UdpStream {
+    sock: Arc<UdpSocket>,
+    // Current peer, for a stream from a listening socket.
+    peer: Option<Arc<Mutex<SocketAddr>>>,
+    closed: Arc<AtomicBool>,
+    dgram: Vec<u8>,
+    len: usize,
+    pos: usize,
+}
+
+/// Connects a UDP socket to `host:port`, bound to the local port `bind` if
+/// given (e.g. for peers that reply to a known port) or else to any port.
+///
+/// ```
+/// use std::io::{Read, Write};
+/// use std::net::UdpSocket;
+/// use msptest::net;
+///
+/// // Local responder, echoing each datagram.
+/// let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+/// let port = responder.local_addr().unwrap().port();
+/// std::thread::spawn(move || {
+///     let mut buf = [0u8; 1024];
+///     while let Ok((n, peer)) = responder.recv_from(&mut buf) {
+///         _ = responder.send_to(&buf[..n], peer);
+///     }
+/// });
+///
+/// let mut u = net::udp_connect("127.0.0.1", port, None).unwrap();
+/// u.write_all(&[0x55; 600]).unwrap();
+/// let mut buf = [0u8; 256];
+/// let mut got = 0;
+/// while got < 600 {
+///     got += u.read(&mut buf).unwrap();
+/// }
+/// assert_eq!(got, 600);
+/// ```
+pub fn udp_connect(host: &str, port: u16, bind: Option<u16>) -> io::Result<UdpStream> {
+    let remote = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(no_address)?;
+    let sock = UdpSocket::bind(bind_addr(&remote, bind.unwrap_or(0)))?;
+    sock.connect(remote)?;
+    sock.set_read_timeout(Some(UDP_POLL))?;
+    Ok(UdpStream {
+        sock: Arc::new(sock),
+        peer: None,
+        closed: Arc::new(AtomicBool::new(false)),
+        dgram: Vec::new(),
+        len: 0,
+        pos: 0,
+    })
+}
+
+impl UdpStream {
+    /// Returns another handle to the socket, e.g. for a writer; buffered
+    /// input is not shared.
+    pub fn try_clone(&self) -> io::Result<UdpStream> {
+        Ok(UdpStream {
+            sock: self.sock.clone(),
+            peer: self.peer.clone(),
+            closed: self.closed.clone(),
+            dgram: Vec::new(),
+            len: 0,
+            pos: 0,
+        })
+    }
+
+    /// Returns the local address the socket is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.sock.local_addr()
+    }
+
+    /// Returns the address of the peer.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self.peer {
+            Some(ref p) => Ok(*p.lock().unwrap()),
+            None => self.sock.peer_addr(),
+        }
+    }
+
+    /// Stops the stream: a read blocked in any handle returns `Ok(0)`
+    /// (within 200ms) and subsequent writes fail.
+    pub fn shutdown(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+// The read timeout used to poll for shutdown expired.
+fn is_poll_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+impl Read for UdpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.len {
+            if self.closed.load(Ordering::SeqCst) {
+                return Ok(0);
+            }
+            if self.dgram.is_empty() {
+                self.dgram = vec![0; MAX_DATAGRAM];
+            }
+            self.pos = 0;
+            let res = match self.peer {
+                Some(ref peer) => self.sock.recv_from(&mut self.dgram).map(|(n, from)| {
+                    *peer.lock().unwrap() = from;
+                    n
+                }),
+                None => self.sock.recv(&mut self.dgram),
+            };
+            self.len = match res {
+                Ok(n) => n,
+                Err(e) if is_poll_timeout(&e) => 0,
+                // Windows reports an ICMP unreachable for an earlier send_to
+                // on the next receive; only fatal for a connected socket.
+                Err(e) if self.peer.is_some() && e.kind() == io::ErrorKind::ConnectionReset => 0,
+                Err(e) => return Err(e),
+            };
+        }
+        let n = buf.len().min(self.len - self.pos);
+        buf[..n].copy_from_slice(&self.dgram[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl Write for UdpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        let buf = &buf[..buf.len().min(MAX_SEND)];
+        match self.peer {
+            Some(ref peer) => {
+                let to = *peer.lock().unwrap();
+                self.sock.send_to(buf, to)
+            }
+            None => self.sock.send(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Listening UDP socket for peers that send first, e.g. telemetry bridges
+/// that connect out to the ground station.
+#[derive(Debug)]
+pub struct UdpServer {
+    sock: Arc<UdpSocket>,
+}
+
+impl UdpServer {
+    /// Binds to `host:port`.
+    pub fn bind(host: &str, port: u16) -> io::Result<UdpServer> {
+        let sock = UdpSocket::bind((host, port))?;
+        sock.set_read_timeout(Some(UDP_POLL))?;
+        Ok(UdpServer {
+            sock: Arc::new(sock),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.sock.local_addr()
+    }
+
+    /// Waits for a datagram and returns a stream that replies to its sender
+    /// (and subsequently to the sender of the latest datagram). The
+    /// datagram is the first data read from the stream.
+    pub fn accept(&self) -> io::Result<UdpStream> {
+        let mut dgram = vec![0; MAX_DATAGRAM];
+        loop {
+            match self.sock.recv_from(&mut dgram) {
+                Ok((len, from)) => {
+                    return Ok(UdpStream {
+                        sock: self.sock.clone(),
+                        peer: Some(Arc::new(Mutex::new(from))),
+                        closed: Arc::new(AtomicBool::new(false)),
+                        dgram,
+                        len,
+                        pos: 0,
+                    })
+                }
+                Err(e) if is_poll_timeout(&e) => (),
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msp;
+    use std::thread;
+    use std::time::Instant;
+
+    fn pair() -> (UdpStream, UdpSocket) {
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = peer.local_addr().unwrap().port();
+        let u = udp_connect("127.0.0.1", port, None).unwrap();
+        peer.connect(u.local_addr().unwrap()).unwrap();
+        (u, peer)
+    }
+
+    #[test]
+    fn jumbo_frame_is_one_datagram() {
+        let (mut u, peer) = pair();
+        let frame = msp::encode_msp(msp::MSG_NAME, &[0x55; 600]).unwrap();
+        u.write_all(&frame).unwrap();
+        let mut dgram = vec![0; MAX_DATAGRAM];
+        let n = peer.recv(&mut dgram).unwrap();
+        assert_eq!(&dgram[..n], &frame[..]);
+
+        // Read back through a small buffer, followed by another datagram.
+        peer.send(&frame).unwrap();
+        peer.send(b"next").unwrap();
+        let mut got = Vec::new();
+        let mut buf = [0; 256];
+        while got.len() < frame.len() {
+            let n = u.read(&mut buf).unwrap();
+            got.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(got, frame);
+        let n = u.read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"next");
+    }
+
+    #[test]
+    fn oversized_write_is_split() {
+        let (mut u, peer) = pair();
+        let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        u.write_all(&data).unwrap();
+        let mut dgram = vec![0; MAX_DATAGRAM];
+        let mut sizes = Vec::new();
+        let mut got = Vec::new();
+        while got.len() < data.len() {
+            let n = peer.recv(&mut dgram).unwrap();
+            sizes.push(n);
+            got.extend_from_slice(&dgram[..n]);
+        }
+        assert_eq!(sizes, [MAX_SEND, data.len() - MAX_SEND]);
+        assert_eq!(got, data);
+    }
+
+    #[test]
+    fn shutdown_unblocks_reader() {
+        let (u, _peer) = pair();
+        let mut rd = u.try_clone().unwrap();
+        let h = thread::spawn(move || {
+            let mut buf = [0; 16];
+            rd.read(&mut buf)
+        });
+        thread::sleep(Duration::from_millis(50));
+        let st = Instant::now();
+        u.shutdown();
+        assert_eq!(h.join().unwrap().unwrap(), 0);
+        assert!(st.elapsed() < UDP_POLL * 3);
+        assert!(u.try_clone().unwrap().write(b"x").is_err());
+    }
+}
@@ -1,50 +1,461 @@
-use url::Url;
+//! Device name / URI parsing.
+
+use crate::fault::FaultOptions;
+use crate::serial_options::{FlowControl, Parity, SerialOptions};
+use crate::transport::Endpoint;
 use regex::Regex;
+use std::borrow::Cow;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+use url::Url;
 
-pub fn parse_uri_dev (arg: &str) -> (String, u32, u8) {
-    let mut name: String ;
-    let mut param: u32 = 0;
-    let mut typ: u8 = 0;
-    let mut need_split = false;
-    match Url::parse(arg) {
-        Ok(u) => {
-            match u.host_str() {
-                Some(n) => {
-                    name = n.to_string();
-		    typ = match u.scheme() {
-			"tcp" => 1,
-			"udp" => 2,
-			_ => 0,
-		    };
-                    match u.port() {
-                        Some(d) => param = u32::from(d),
-                        None => param = 5760
-                        }
-                }
-                None => {
-                    name = u.path().to_string();
-                    need_split = true;
+/// Error returned for an invalid device specification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UriError {
+    /// The specification is empty.
+    Empty,
+    /// The baud rate is not a positive integer.
+    BadBaud(String),
+    /// The URI scheme is not supported.
+    UnknownScheme(String),
+    /// A network URI has no host.
+    MissingHost,
+    /// The port is not in the range 1-65535.
+    BadPort,
+    /// An option (query parameter) is unknown or has a bad value.
+    BadOption(String),
+    /// The URI is otherwise malformed.
+    Invalid(String),
+}
+
+impl fmt::Display for UriError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UriError::Empty => write!(f, "empty device name"),
+            UriError::BadBaud(b) => write!(f, "invalid baud rate \"{}\"", b),
+            UriError::UnknownScheme(s) => {
+                write!(
+                    f,
+                    "unknown scheme \"{}\" (expected serial, tcp, udp, tcp-listen or udp-listen)",
+                    s
+                )
+            }
+            UriError::MissingHost => write!(f, "missing host"),
+            UriError::BadPort => write!(f, "port out of range (1-65535)"),
+            UriError::BadOption(o) => write!(f, "invalid option \"{}\"", o),
+            UriError::Invalid(e) => write!(f, "invalid URI ({})", e),
+        }
+    }
+}
+
+impl std::error::Error for UriError {}
+
+impl From<UriError> for io::Error {
+    fn from(e: UriError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }
+}
+
+/// Parses a device specification into an [`Endpoint`].
+///
+/// Serial devices may include a baud rate (default 115200), e.g.
+/// `/dev/ttyACM0@115200` or `COM17:57600`; network links are
+/// `tcp://host[:port]` or `udp://host[:port]` (default port 5760). A UDP
+/// link may be bound to a fixed local port with `?bind=port`.
+///
+/// `tcp-listen://[host][:port]` and `udp-listen://[host][:port]` wait for a
+/// peer to connect (or send) to the local address instead; the host
+/// defaults to `0.0.0.0`.
+///
+/// Serial line settings may be given as query parameters of a `serial://`
+/// URI, e.g. `serial:///dev/ttyUSB0?baud=57600&flow=rtscts&parity=even&stop=2`.
+/// The parameters are `baud`, `bits` (5-8), `parity` (`none`, `odd`,
+/// `even`), `stop` (1, 2) and `flow` (`none`, `rtscts`, `xonxoff`).
+///
+/// Any of these prefixed by `fault+` injects faults for testing, e.g.
+/// `fault+tcp://localhost:5760?drop=0.01&flip=0.001&delay=20ms&dup=0.005&seed=7`;
+/// see [`FaultOptions`]. The delay may be in `us`, `ms` (the default) or `s`.
+///
+/// `replay://path?speed=1.0` replays a capture file (see
+/// [`crate::capture`]); the path may be relative (`replay://session.cap`)
+/// or absolute (`replay:///tmp/session.cap`). A speed of 0 replays without
+/// delays; other speeds must be at least [`MIN_REPLAY_SPEED`].
+pub fn parse_uri_dev(arg: &str) -> Result<Endpoint, UriError> {
+    if arg.is_empty() {
+        return Err(UriError::Empty);
+    }
+
+    if let Some(inner) = arg.strip_prefix("fault+") {
+        return parse_fault_uri(inner);
+    }
+
+    if let Some(rest) = arg.strip_prefix("replay://") {
+        return parse_replay_uri(rest);
+    }
+
+    if let Some(i) = arg.find("://") {
+        // Listen URIs may omit the host (any address), e.g. `udp-listen://:14555`.
+        let rest = &arg[i + 3..];
+        let arg: Cow<str> =
+            if arg[..i].ends_with("-listen") && (rest.is_empty() || rest.starts_with([':', '?'])) {
+                format!("{}://0.0.0.0{}", &arg[..i], rest).into()
+            } else {
+                arg.into()
+            };
+        let u = match Url::parse(&arg) {
+            Ok(u) => u,
+            Err(url::ParseError::InvalidPort) => return Err(UriError::BadPort),
+            Err(url::ParseError::EmptyHost) => return Err(UriError::MissingHost),
+            Err(e) => return Err(UriError::Invalid(e.to_string())),
+        };
+        if u.scheme() == "serial" {
+            return parse_serial_uri(&u);
+        }
+        let host = match u.host_str() {
+            Some(h) if !h.is_empty() => h.trim_start_matches('[').trim_end_matches(']').to_string(),
+            _ => return Err(UriError::MissingHost),
+        };
+        let port = match u.port() {
+            Some(0) => return Err(UriError::BadPort),
+            Some(p) => p,
+            None => 5760,
+        };
+        // Only udp:// has options.
+        if matches!(u.scheme(), "tcp" | "tcp-listen" | "udp-listen") {
+            if let Some((k, v)) = u.query_pairs().next() {
+                return Err(UriError::BadOption(format!("{}={}", k, v)));
+            }
+        }
+        return match u.scheme() {
+            "tcp" => Ok(Endpoint::Tcp { host, port }),
+            "udp" => {
+                let mut bind = None;
+                for (k, v) in u.query_pairs() {
+                    match (k.as_ref(), v.parse::<u16>()) {
+                        ("bind", Ok(p)) if p > 0 => bind = Some(p),
+                        _ => return Err(UriError::BadOption(format!("{}={}", k, v))),
+                    }
                 }
+                Ok(Endpoint::Udp { host, port, bind })
             }
+            "tcp-listen" => Ok(Endpoint::TcpListen { host, port }),
+            "udp-listen" => Ok(Endpoint::UdpListen { host, port }),
+            s => Err(UriError::UnknownScheme(s.to_string())),
+        };
+    }
+
+    let re = Regex::new(r"[:@]").unwrap();
+    let mut parts = re.splitn(arg, 2);
+    let name = parts.next().unwrap_or_default().to_string();
+    if name.is_empty() {
+        return Err(UriError::Empty);
+    }
+    let baud = match parts.next() {
+        Some(b) => match b.parse::<u32>() {
+            Ok(n) if n > 0 => n,
+            _ => return Err(UriError::BadBaud(b.to_string())),
         },
-        Err(_) => {
-            name = arg.to_string();
-            need_split = true;
-        }
+        None => 115200,
     };
+    Ok(Endpoint::Serial {
+        name,
+        baud,
+        opts: SerialOptions::default(),
+    })
+}
+
+fn parse_serial_uri(u: &Url) -> Result<Endpoint, UriError> {
+    if u.host_str().is_some_and(|h| !h.is_empty()) {
+        return Err(UriError::Invalid(
+            "serial device must be a path, e.g. serial:///dev/ttyUSB0".to_string(),
+        ));
+    }
+    let mut name = u.path();
+    if cfg!(windows) {
+        name = name.trim_start_matches('/');
+    }
+    if name.is_empty() || name == "/" {
+        return Err(UriError::Empty);
+    }
+
+    let mut baud = 115200;
+    let mut opts = SerialOptions::default();
+    for (k, v) in u.query_pairs() {
+        let bad = || UriError::BadOption(format!("{}={}", k, v));
+        match k.as_ref() {
+            "baud" => {
+                baud = match v.parse::<u32>() {
+                    Ok(n) if n > 0 => n,
+                    _ => return Err(UriError::BadBaud(v.to_string())),
+                }
+            }
+            "bits" => {
+                opts.data_bits = match v.parse::<u8>() {
+                    Ok(n @ 5..=8) => n,
+                    _ => return Err(bad()),
+                }
+            }
+            "parity" => {
+                opts.parity = match v.as_ref() {
+                    "none" | "n" => Parity::None,
+                    "odd" | "o" => Parity::Odd,
+                    "even" | "e" => Parity::Even,
+                    _ => return Err(bad()),
+                }
+            }
+            "stop" => {
+                opts.stop_bits = match v.as_ref() {
+                    "1" => 1,
+                    "2" => 2,
+                    _ => return Err(bad()),
+                }
+            }
+            "flow" => {
+                opts.flow = match v.as_ref() {
+                    "none" => FlowControl::None,
+                    "rtscts" => FlowControl::RtsCts,
+                    "xonxoff" => FlowControl::XonXoff,
+                    _ => return Err(bad()),
+                }
+            }
+            _ => return Err(bad()),
+        }
+    }
+    Ok(Endpoint::Serial {
+        name: name.to_string(),
+        baud,
+        opts,
+    })
+}
 
-    if need_split {
-        let re = Regex::new(r"[:@]").unwrap();
-        let rname = name.clone();
-        let mut parts = re.split(&rname);
-        match parts.next() {
-            Some(n) => name = n.to_string(),
-            None => (),
+// The fault parameters are removed and the rest parsed as the inner link.
+fn parse_fault_uri(arg: &str) -> Result<Endpoint, UriError> {
+    let (base, query) = arg.split_once('?').unwrap_or((arg, ""));
+    let mut faults = FaultOptions::default();
+    let mut rest = Vec::new();
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+        let bad = || UriError::BadOption(pair.to_string());
+        let prob = || match v.parse::<f64>() {
+            Ok(p) if (0.0..=1.0).contains(&p) => Ok(p),
+            _ => Err(bad()),
         };
-        match parts.next() {
-            Some(d) => param = d.parse::<u32>().unwrap(),
-            None => param = 115200,
+        match k {
+            "drop" => faults.drop = prob()?,
+            "flip" => faults.flip = prob()?,
+            "dup" => faults.dup = prob()?,
+            "delay" => faults.delay = parse_delay(v).ok_or_else(bad)?,
+            "seed" => faults.seed = v.parse().map_err(|_| bad())?,
+            _ => rest.push(pair),
+        }
+    }
+    let inner = if rest.is_empty() {
+        base.to_string()
+    } else {
+        format!("{}?{}", base, rest.join("&"))
+    };
+    Ok(Endpoint::Fault {
+        inner: Box::new(parse_uri_dev(&inner)?),
+        faults,
+    })
+}
+
+// The unit is a suffix, so exponents (e.g. `1e3ms`) are accepted.
+fn parse_delay(v: &str) -> Option<Duration> {
+    let (n, scale) = if let Some(n) = v.strip_suffix("us") {
+        (n, 1e-6)
+    } else if let Some(n) = v.strip_suffix("ms") {
+        (n, 1e-3)
+    } else if let Some(n) = v.strip_suffix('s') {
+        (n, 1.0)
+    } else {
+        (v, 1e-3)
+    };
+    let n = n
+        .parse::<f64>()
+        .ok()
+        .filter(|n| n.is_finite() && *n >= 0.0)?;
+    // Out of range delays are rejected rather than panicking.
+    Duration::try_from_secs_f64(n * scale).ok()
+}
+
+/// The slowest replay speed accepted.
+pub const MIN_REPLAY_SPEED: f64 = 0.001;
+
+fn parse_replay_uri(arg: &str) -> Result<Endpoint, UriError> {
+    let (path, query) = arg.split_once('?').unwrap_or((arg, ""));
+    if path.is_empty() {
+        return Err(UriError::Empty);
+    }
+    let mut speed = 1.0;
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        match pair.split_once('=') {
+            Some(("speed", v)) => {
+                speed = match v.parse::<f64>() {
+                    Ok(s) if s.is_finite() && (s == 0.0 || s >= MIN_REPLAY_SPEED) => s,
+                    _ => return Err(UriError::BadOption(pair.to_string())),
+                }
+            }
+            _ => return Err(UriError::BadOption(pair.to_string())),
+        }
+    }
+    Ok(Endpoint::Replay {
+        path: path.to_string(),
+        speed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn serial(name: &str, baud: u32) -> Endpoint {
+        Endpoint::Serial {
+            name: name.to_string(),
+            baud,
+            opts: SerialOptions::default(),
+        }
+    }
+
+    fn tcp(host: &str, port: u16) -> Endpoint {
+        Endpoint::Tcp {
+            host: host.to_string(),
+            port,
+        }
+    }
+
+    #[test]
+    fn valid() {
+        let udp = |host: &str, port, bind| Endpoint::Udp {
+            host: host.to_string(),
+            port,
+            bind,
         };
+        let cases = [
+            ("COM17", serial("COM17", 115200)),
+            ("/dev/ttyACM0:57600", serial("/dev/ttyACM0", 57600)),
+            ("/dev/ttyUSB0@115200", serial("/dev/ttyUSB0", 115200)),
+            ("tcp://host", tcp("host", 5760)),
+            ("tcp://host:5761", tcp("host", 5761)),
+            ("tcp://[::1]:5762", tcp("::1", 5762)),
+            ("udp://host", udp("host", 5760, None)),
+            (
+                "udp://host:14550?bind=14551",
+                udp("host", 14550, Some(14551)),
+            ),
+            (
+                "tcp-listen://:5761",
+                Endpoint::TcpListen {
+                    host: "0.0.0.0".to_string(),
+                    port: 5761,
+                },
+            ),
+            (
+                "tcp-listen://127.0.0.1",
+                Endpoint::TcpListen {
+                    host: "127.0.0.1".to_string(),
+                    port: 5760,
+                },
+            ),
+            (
+                "udp-listen://",
+                Endpoint::UdpListen {
+                    host: "0.0.0.0".to_string(),
+                    port: 5760,
+                },
+            ),
+        ];
+        for (uri, ep) in cases {
+            assert_eq!(parse_uri_dev(uri).as_ref(), Ok(&ep), "{}", uri);
+            // Display gives an equivalent specification.
+            assert_eq!(parse_uri_dev(&ep.to_string()), Ok(ep), "{}", uri);
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        let bad = |o: &str| UriError::BadOption(o.to_string());
+        let cases = [
+            ("", UriError::Empty),
+            ("@115200", UriError::Empty),
+            ("/dev/ttyUSB0@fast", UriError::BadBaud("fast".to_string())),
+            ("COM3:0", UriError::BadBaud("0".to_string())),
+            ("ws://host:80", UriError::UnknownScheme("ws".to_string())),
+            ("tcp://host:0", UriError::BadPort),
+            ("tcp://host:65536", UriError::BadPort),
+            ("udp-listen://:0", UriError::BadPort),
+            ("tcp://", UriError::MissingHost),
+            ("udp://:5760", UriError::MissingHost),
+            ("tcp://host?bind=1", bad("bind=1")),
+            ("tcp-listen://:5761?x=1", bad("x=1")),
+            ("udp-listen://?bind=1", bad("bind=1")),
+            ("udp://host?bind=0", bad("bind=0")),
+            ("udp://host?port=1", bad("port=1")),
+            ("fault+tcp://host?drop=2", bad("drop=2")),
+            ("fault+tcp://host?drop=0.1&x=1", bad("x=1")),
+            ("replay://f?speed=-1", bad("speed=-1")),
+            ("replay://f?speed=1e-300", bad("speed=1e-300")),
+            ("replay://f?speed=inf", bad("speed=inf")),
+        ];
+        for (uri, err) in cases {
+            assert_eq!(parse_uri_dev(uri), Err(err), "{}", uri);
+        }
+    }
+
+    #[test]
+    fn ipv6_display() {
+        for uri in [
+            "tcp://[::1]:5760",
+            "udp://[fe80::1]:14550?bind=14551",
+            "tcp-listen://[::]:5761",
+            "udp-listen://[::1]:5762",
+        ] {
+            assert_eq!(parse_uri_dev(uri).unwrap().to_string(), uri);
+        }
+    }
+
+    fn faults(uri: &str) -> FaultOptions {
+        match parse_uri_dev(uri) {
+            Ok(Endpoint::Fault { faults, .. }) => faults,
+            r => panic!("{}: {:?}", uri, r),
+        }
+    }
+
+    #[test]
+    fn fault_delay() {
+        let d = |q: &str| faults(&format!("fault+tcp://localhost?delay={}", q)).delay;
+        assert_eq!(d("20"), Duration::from_millis(20));
+        assert_eq!(d("500us"), Duration::from_micros(500));
+        assert_eq!(d("1e3ms"), Duration::from_secs(1));
+        assert_eq!(d("1.5s"), Duration::from_millis(1500));
+        for bad in ["99999999999999999999999s", "-1ms", "1h", "ms", "inf"] {
+            assert_eq!(
+                parse_uri_dev(&format!("fault+tcp://localhost?delay={}", bad)),
+                Err(UriError::BadOption(format!("delay={}", bad)))
+            );
+        }
+    }
+
+    #[test]
+    fn fault_display_reparses() {
+        let ep = parse_uri_dev("fault+udp://localhost:5761?bind=1234&drop=0.25&delay=500us&seed=9")
+            .unwrap();
+        assert!(
+            matches!(ep, Endpoint::Fault { ref faults, .. } if faults.delay == Duration::from_micros(500))
+        );
+        assert_eq!(parse_uri_dev(&ep.to_string()), Ok(ep));
+    }
+
+    #[test]
+    fn serial_options_display_reparses() {
+        for uri in [
+            "serial:///dev/ttyUSB0?baud=57600&flow=rtscts&parity=even&stop=2",
+            "serial:///dev/ttyS1?bits=7&parity=odd",
+            "fault+serial:///dev/ttyUSB0?flow=xonxoff&drop=0.5",
+        ] {
+            let ep = parse_uri_dev(uri).unwrap();
+            assert_eq!(parse_uri_dev(&ep.to_string()), Ok(ep), "{}", uri);
+        }
     }
-    (name,param,typ)
 }
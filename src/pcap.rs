@@ -0,0 +1,277 @@
+//! pcapng export of MSP traffic, e.g. to inspect it in Wireshark.
+//!
+//! Each MSP frame, as delimited by [`MspParser`] (so including frames that
+//! fail their checksum), is written as a packet with the frame bytes, the
+//! time it was received and its direction (inbound from the FC, outbound
+//! to it). Frames failing their checksum have the CRC error flag set in
+//! `epb_flags` and a comment. Each connection in a capture is a separate
+//! interface, named after the link, with link type [`LINKTYPE_USER0`].
+
+use crate::capture::{CaptureFile, Direction, Event};
+use crate::msp::{MSPMsg, MSPRes, MspParser};
+use std::io;
+use std::io::{Read, Write};
+
+/// The link type of the packets: `LINKTYPE_USER0`, reserved for private
+/// use, e.g. with a Lua dissector.
+pub const LINKTYPE_USER0: u16 = 147;
+
+const SHB: u32 = 0x0a0d_0d0a;
+const IDB: u32 = 1;
+const EPB: u32 = 6;
+
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const SHB_USERAPPL: u16 = 4;
+const IF_NAME: u16 = 2;
+const IF_DESCRIPTION: u16 = 3;
+const IF_TSRESOL: u16 = 9;
+const EPB_FLAGS: u16 = 2;
+
+// epb_flags bits.
+const FLAG_INBOUND: u32 = 1;
+const FLAG_OUTBOUND: u32 = 2;
+const FLAG_CRC_ERROR: u32 = 1 << 24;
+
+fn pad(v: &mut Vec<u8>) {
+    v.resize(v.len().next_multiple_of(4), 0);
+}
+
+fn option(v: &mut Vec<u8>, code: u16, val: &[u8]) {
+    v.extend_from_slice(&code.to_le_bytes());
+    v.extend_from_slice(&(val.len() as u16).to_le_bytes());
+    v.extend_from_slice(val);
+    pad(v);
+}
+
+fn end_options(v: &mut Vec<u8>) {
+    option(v, OPT_END, &[]);
+}
+
+/// pcapng file writer (little endian, microsecond timestamps).
+pub struct PcapWriter<W> {
+    out: W,
+    interfaces: u32,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Writes the section header to `out`.
+    pub fn new(out: W) -> io::Result<Self> {
+        let mut w = Self { out, interfaces: 0 };
+        let mut b = Vec::new();
+        b.extend_from_slice(&0x1a2b_3c4du32.to_le_bytes());
+        b.extend_from_slice(&1u16.to_le_bytes());
+        b.extend_from_slice(&0u16.to_le_bytes());
+        // Section length not specified.
+        b.extend_from_slice(&(-1i64).to_le_bytes());
+        let app = format!("msptest {}", env!("CARGO_PKG_VERSION"));
+        option(&mut b, SHB_USERAPPL, app.as_bytes());
+        end_options(&mut b);
+        w.block(SHB, &b)?;
+        Ok(w)
+    }
+
+    fn block(&mut self, typ: u32, body: &[u8]) -> io::Result<()> {
+        let len = (body.len() as u32 + 12).to_le_bytes();
+        let mut v = Vec::with_capacity(body.len() + 12);
+        v.extend_from_slice(&typ.to_le_bytes());
+        v.extend_from_slice(&len);
+        v.extend_from_slice(body);
+        v.extend_from_slice(&len);
+        self.out.write_all(&v)
+    }
+
+    /// Adds an interface (link) and returns its id.
+    pub fn add_interface(&mut self, name: &str, description: &str) -> io::Result<u32> {
+        let mut b = Vec::new();
+        b.extend_from_slice(&LINKTYPE_USER0.to_le_bytes());
+        b.extend_from_slice(&0u16.to_le_bytes());
+        // No snapshot length limit.
+        b.extend_from_slice(&0u32.to_le_bytes());
+        option(&mut b, IF_NAME, name.as_bytes());
+        option(&mut b, IF_DESCRIPTION, description.as_bytes());
+        option(&mut b, IF_TSRESOL, &[6]);
+        end_options(&mut b);
+        self.block(IDB, &b)?;
+        self.interfaces += 1;
+        Ok(self.interfaces - 1)
+    }
+
+    /// Writes a frame received at `time_us` (microseconds since the Unix
+    /// epoch) on interface `iface`.
+    pub fn write_frame(
+        &mut self,
+        iface: u32,
+        time_us: u64,
+        dir: Direction,
+        frame: &[u8],
+        crc_error: bool,
+    ) -> io::Result<()> {
+        let mut b = Vec::with_capacity(frame.len() + 48);
+        b.extend_from_slice(&iface.to_le_bytes());
+        b.extend_from_slice(&((time_us >> 32) as u32).to_le_bytes());
+        b.extend_from_slice(&(time_us as u32).to_le_bytes());
+        b.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        b.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        b.extend_from_slice(frame);
+        pad(&mut b);
+        let mut flags = match dir {
+            Direction::FromFc => FLAG_INBOUND,
+            Direction::ToFc => FLAG_OUTBOUND,
+        };
+        if crc_error {
+            flags |= FLAG_CRC_ERROR;
+            option(&mut b, OPT_COMMENT, b"CRC error");
+        }
+        option(&mut b, EPB_FLAGS, &flags.to_le_bytes());
+        end_options(&mut b);
+        self.block(EPB, &b)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Splits a byte stream into raw MSP frames, using [`MspParser`].
+#[derive(Debug, Default, Clone)]
+pub struct FrameSplitter {
+    parser: MspParser,
+    raw: Vec<u8>,
+}
+
+impl FrameSplitter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Discards any partial frame.
+    pub fn reset(&mut self) {
+        self.parser.reset();
+        self.raw.clear();
+    }
+
+    /// Processes `data`, calling `f` with the bytes and decoded message of
+    /// each frame completed. Bytes between frames are discarded.
+    pub fn push<F>(&mut self, data: &[u8], mut f: F) -> io::Result<()>
+    where
+        F: FnMut(&[u8], &MSPMsg) -> io::Result<()>,
+    {
+        for &e in data {
+            if self.parser.is_idle() {
+                self.raw.clear();
+            }
+            self.raw.push(e);
+            let msg = self.parser.push(e);
+            // A frame may restart at a later `$` (e.g. `$$M>`).
+            if self.parser.is_frame_start() {
+                self.raw.clear();
+                self.raw.push(e);
+            }
+            if let Some(msg) = msg {
+                f(&self.raw, &msg)?;
+                self.raw.clear();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Converts captured traffic to pcapng as it is recorded or read from a
+/// capture file.
+///
+/// ```
+/// use msptest::capture::Direction;
+/// use msptest::msp;
+/// use msptest::pcap::PcapExporter;
+///
+/// let mut x = PcapExporter::new(Vec::new()).unwrap();
+/// x.session("tcp://localhost:5760", 0).unwrap();
+/// let mut req = msp::encode_msp(msp::MSG_NAME, &[]).unwrap();
+/// x.data(1_000_000, Direction::ToFc, &req[..3]).unwrap();
+/// x.data(1_000_100, Direction::ToFc, &req[3..]).unwrap();
+/// // Corrupted checksum.
+/// *req.last_mut().unwrap() ^= 1;
+/// x.data(1_000_200, Direction::ToFc, &req).unwrap();
+/// assert_eq!((x.frames(), x.crc_errors()), (2, 1));
+/// ```
+pub struct PcapExporter<W> {
+    out: PcapWriter<W>,
+    iface: Option<u32>,
+    // Indexed by direction: [to FC, from FC].
+    splitters: [FrameSplitter; 2],
+    frames: usize,
+    crc_errors: usize,
+}
+
+impl<W: Write> PcapExporter<W> {
+    pub fn new(out: W) -> io::Result<Self> {
+        Ok(Self {
+            out: PcapWriter::new(out)?,
+            iface: None,
+            splitters: Default::default(),
+            frames: 0,
+            crc_errors: 0,
+        })
+    }
+
+    /// Starts a connection to `endpoint`, on a new interface.
+    pub fn session(&mut self, endpoint: &str, baud: u32) -> io::Result<()> {
+        let mut desc = format!("msptest {}", env!("CARGO_PKG_VERSION"));
+        if baud > 0 {
+            desc += &format!(", {} baud", baud);
+        }
+        self.iface = Some(self.out.add_interface(endpoint, &desc)?);
+        self.splitters.iter_mut().for_each(FrameSplitter::reset);
+        Ok(())
+    }
+
+    /// Processes data transferred at `time_us` (microseconds since the Unix
+    /// epoch), writing the frames it completes.
+    pub fn data(&mut self, time_us: u64, dir: Direction, data: &[u8]) -> io::Result<()> {
+        let iface = match self.iface {
+            Some(i) => i,
+            None => {
+                let i = self.out.add_interface("msptest", "")?;
+                self.iface = Some(i);
+                i
+            }
+        };
+        let (out, frames, crc_errors) = (&mut self.out, &mut self.frames, &mut self.crc_errors);
+        self.splitters[(dir == Direction::FromFc) as usize].push(data, |raw, msg| {
+            let crc = matches!(msg.ok, MSPRes::Crc);
+            *frames += 1;
+            *crc_errors += crc as usize;
+            out.write_frame(iface, time_us, dir, raw, crc)
+        })
+    }
+
+    /// Number of frames written.
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// Number of frames written that failed their checksum.
+    pub fn crc_errors(&self) -> usize {
+        self.crc_errors
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Converts a capture to pcapng, returning the number of frames and of
+/// those that failed their checksum.
+pub fn export<R: Read, W: Write>(cap: CaptureFile<R>, out: W) -> io::Result<(usize, usize)> {
+    let mut x = PcapExporter::new(out)?;
+    for rec in cap {
+        let rec = rec?;
+        match rec.event {
+            Event::Session { endpoint, baud, .. } => x.session(&endpoint, baud)?,
+            Event::Data { dir, data } => x.data(rec.time_us, dir, &data)?,
+        }
+    }
+    x.flush()?;
+    Ok((x.frames(), x.crc_errors()))
+}
@@ -0,0 +1,508 @@
+// MSP multiplexing proxy: shares one FC link between several clients
+// connected over TCP or UDP. Client requests are sent to the FC one at a
+// time and each reply is returned to the client that sent the request;
+// unsolicited frames (MSG_DEBUGMSG) go to every client. Replies arriving
+// after REPLY_TIMEOUT are matched in order against the requests that timed
+// out; those that match nothing are dropped.
+
+use crossbeam_channel::{select, tick, unbounded, Sender};
+use msptest::msp::{self, MSPMsg, MSPRes, MspParser};
+use msptest::parse_dev;
+use msptest::transport::{Endpoint, Transport};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Listen address when none is given.
+const DEFAULT_LISTEN: &str = "tcp-listen://0.0.0.0:5770";
+
+/// Time allowed for the FC to answer before the next request is sent.
+const REPLY_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Time after which a timed out request's reply is no longer expected.
+const LATE_REPLY_LIMIT: Duration = Duration::from_secs(5);
+
+/// A UDP client that has sent nothing for this long is dropped.
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+enum Event {
+    Joined(usize, String, Box<dyn Write + Send>),
+    Request(usize, MSPMsg),
+    Gone(usize),
+}
+
+// Request sent to the FC and awaiting its reply.
+struct Pending {
+    client: usize,
+    cmd: u16,
+    sent: Instant,
+}
+
+// Routes client requests to the FC, one at a time, and its replies back.
+#[derive(Default)]
+struct Router {
+    clients: HashMap<usize, Box<dyn Write + Send>>,
+    queue: VecDeque<(usize, MSPMsg)>,
+    pending: Option<Pending>,
+    // Timed out requests, oldest first, whose replies may still arrive.
+    late: VecDeque<Pending>,
+}
+
+impl Router {
+    fn joined(&mut self, id: usize, wr: Box<dyn Write + Send>) {
+        self.clients.insert(id, wr);
+    }
+
+    fn gone(&mut self, id: usize) {
+        self.clients.remove(&id);
+    }
+
+    fn request(&mut self, id: usize, msg: MSPMsg) {
+        self.queue.push_back((id, msg));
+    }
+
+    // The FC answers in order, so a reply is for the oldest request with
+    // its command; any older requests have lost their replies.
+    fn reply(&mut self, msg: &MSPMsg) {
+        let Some(buf) = encode_reply(msg) else {
+            return;
+        };
+        let late = self.late.iter().position(|p| p.cmd == msg.cmd);
+        let client = match late {
+            Some(i) => {
+                self.late.drain(..i);
+                self.late.pop_front().map(|p| p.client)
+            }
+            None if self.pending.as_ref().is_some_and(|p| p.cmd == msg.cmd) => {
+                self.late.clear();
+                self.pending.take().map(|p| p.client)
+            }
+            None => None,
+        };
+        match client {
+            Some(id) => {
+                if let Some(wr) = self.clients.get_mut(&id) {
+                    if wr.write_all(&buf).is_err() {
+                        self.clients.remove(&id);
+                    }
+                }
+            }
+            None if msg.cmd == msp::MSG_DEBUGMSG => {
+                self.clients.retain(|_, wr| wr.write_all(&buf).is_ok())
+            }
+            None => (),
+        }
+    }
+
+    // Gives up waiting for the pending request at `now`.
+    fn expire(&mut self, now: Instant) {
+        if self
+            .pending
+            .as_ref()
+            .is_some_and(|p| now.duration_since(p.sent) > REPLY_TIMEOUT)
+        {
+            self.late.extend(self.pending.take());
+        }
+        self.late
+            .retain(|p| now.duration_since(p.sent) <= LATE_REPLY_LIMIT);
+    }
+
+    // Sends queued requests until one is awaiting its reply.
+    fn send_next<W: Write>(&mut self, fc: &mut W) -> io::Result<()> {
+        while self.pending.is_none() {
+            let Some((id, req)) = self.queue.pop_front() else {
+                break;
+            };
+            if !self.clients.contains_key(&id) {
+                continue;
+            }
+            let Some(buf) = encode_request(&req) else {
+                continue;
+            };
+            fc.write_all(&buf)?;
+            self.pending = Some(Pending {
+                client: id,
+                cmd: req.cmd,
+                sent: Instant::now(),
+            });
+        }
+        Ok(())
+    }
+}
+
+// Forwards the requests read from a client.
+fn client_reader<R: Read>(id: usize, mut rd: R, tx: Sender<Event>) {
+    let mut parser = MspParser::new();
+    let mut inp = [0u8; 256];
+    while let Ok(n @ 1..) = rd.read(&mut inp) {
+        for msg in parser.feed(&inp[..n]) {
+            if matches!(msg.ok, MSPRes::Request) && tx.send(Event::Request(id, msg)).is_err() {
+                return;
+            }
+        }
+    }
+    _ = tx.send(Event::Gone(id));
+}
+
+fn tcp_server(host: &str, port: u16, ids: Arc<AtomicUsize>, tx: Sender<Event>) -> io::Result<()> {
+    let listener = TcpListener::bind((host, port))?;
+    thread::spawn(move || {
+        for conn in listener.incoming().flatten() {
+            let Ok(wr) = conn.try_clone() else { continue };
+            _ = conn.set_nodelay(true);
+            let id = ids.fetch_add(1, Ordering::Relaxed);
+            let desc = match conn.peer_addr() {
+                Ok(a) => format!("tcp {}", a),
+                Err(_) => "tcp".to_string(),
+            };
+            if tx.send(Event::Joined(id, desc, Box::new(wr))).is_err() {
+                return;
+            }
+            let tx = tx.clone();
+            thread::spawn(move || client_reader(id, conn, tx));
+        }
+    });
+    Ok(())
+}
+
+// Writer for one UDP client of the shared server socket.
+struct UdpPeer {
+    sock: UdpSocket,
+    addr: SocketAddr,
+}
+
+impl Write for UdpPeer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sock.send_to(buf, self.addr)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Each sender address is a separate client, dropped when idle.
+fn udp_server(host: &str, port: u16, ids: Arc<AtomicUsize>, tx: Sender<Event>) -> io::Result<()> {
+    let sock = UdpSocket::bind((host, port))?;
+    // Wakes the receive loop to expire idle clients.
+    sock.set_read_timeout(Some(Duration::from_secs(1)))?;
+    thread::spawn(move || {
+        let mut peers: HashMap<SocketAddr, (usize, MspParser, Instant)> = HashMap::new();
+        let mut inp = vec![0u8; 65536];
+        loop {
+            let res = sock.recv_from(&mut inp);
+            let mut gone = Vec::new();
+            peers.retain(|_, (id, _, last)| {
+                let idle = last.elapsed() > UDP_IDLE_TIMEOUT;
+                if idle {
+                    gone.push(*id);
+                }
+                !idle
+            });
+            for id in gone {
+                if tx.send(Event::Gone(id)).is_err() {
+                    return;
+                }
+            }
+            let (n, addr) = match res {
+                Ok(r) => r,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue
+                }
+                // ICMP unreachable from an earlier send (Windows).
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(_) => return,
+            };
+            let (id, parser, last) = match peers.entry(addr) {
+                Entry::Occupied(e) => e.into_mut(),
+                Entry::Vacant(e) => {
+                    let Ok(s) = sock.try_clone() else { continue };
+                    let id = ids.fetch_add(1, Ordering::Relaxed);
+                    let wr = UdpPeer { sock: s, addr };
+                    if tx
+                        .send(Event::Joined(id, format!("udp {}", addr), Box::new(wr)))
+                        .is_err()
+                    {
+                        return;
+                    }
+                    e.insert((id, MspParser::new(), Instant::now()))
+                }
+            };
+            *last = Instant::now();
+            for msg in parser.feed(&inp[..n]) {
+                if matches!(msg.ok, MSPRes::Request) && tx.send(Event::Request(*id, msg)).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+fn open_fc(dev: &str, autodev: &str) -> io::Result<Box<dyn Transport>> {
+    let ep = match dev {
+        "auto" => Endpoint::Serial {
+            name: autodev.to_string(),
+            baud: 115200,
+            opts: Default::default(),
+        },
+        _ => parse_dev::parse_uri_dev(dev)?,
+    };
+    let mut tp = ep.transport();
+    tp.open()
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", dev, e)))?;
+    Ok(tp)
+}
+
+// Requests and replies are forwarded in the framing they arrived in, so
+// MSPv2 tunnelled in MSPv1 stays tunnelled for `$M` only links.
+fn encode_request(msg: &MSPMsg) -> Option<Vec<u8>> {
+    match msg.vers {
+        1 => msp::encode_msp(msg.cmd, &msg.data),
+        _ if msg.tunnelled => msp::encode_msp2_over_v1(msg.cmd, &msg.data),
+        _ => msp::encode_msp2(msg.cmd, &msg.data),
+    }
+    .ok()
+}
+
+fn encode_reply(msg: &MSPMsg) -> Option<Vec<u8>> {
+    let error = matches!(msg.ok, MSPRes::Error);
+    if msg.tunnelled {
+        msp::encode_response_over_v1(msg.cmd, &msg.data, error)
+    } else {
+        msp::encode_response(msg.vers, msg.cmd, &msg.data, error)
+    }
+    .ok()
+}
+
+/// Runs the proxy. `args` are the FC device (default `auto`, i.e.
+/// `autodev`) and any `tcp-listen://` / `udp-listen://` addresses for
+/// clients.
+pub fn run(args: &[String], autodev: &str) -> io::Result<()> {
+    let mut dev = "auto";
+    let mut listen = Vec::new();
+    for a in args {
+        if a.contains("-listen://") {
+            listen.push(a.as_str());
+        } else {
+            dev = a;
+        }
+    }
+    if listen.is_empty() {
+        listen.push(DEFAULT_LISTEN);
+    }
+
+    let (etx, erx) = unbounded();
+    let ids = Arc::new(AtomicUsize::new(1));
+    for l in &listen {
+        let res = match parse_dev::parse_uri_dev(l)? {
+            Endpoint::TcpListen { host, port } => tcp_server(&host, port, ids.clone(), etx.clone()),
+            Endpoint::UdpListen { host, port } => udp_server(&host, port, ids.clone(), etx.clone()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "expected tcp-listen:// or udp-listen://",
+            )),
+        };
+        res.map_err(|e| io::Error::new(e.kind(), format!("{}: {}", l, e)))?;
+    }
+    drop(etx);
+
+    let tp = open_fc(dev, autodev)?;
+    let mut fc = tp.writer()?;
+    let rd = tp.reader()?;
+    let (ftx, frx) = unbounded();
+    thread::spawn(move || msp::reader(rd, ftx));
+    println!("Proxying {} on {}", tp.description(), listen.join(", "));
+
+    let mut router = Router::default();
+    let ticks = tick(Duration::from_millis(100));
+
+    loop {
+        select! {
+            recv(frx) -> res => {
+                let msg = res.unwrap_or_default();
+                match msg.ok {
+                    MSPRes::Ok | MSPRes::Error => router.reply(&msg),
+                    MSPRes::Fail => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::BrokenPipe,
+                            format!("{}: link closed", tp.description()),
+                        ))
+                    }
+                    MSPRes::Crc | MSPRes::Request => (),
+                }
+            }
+            recv(erx) -> res => match res {
+                Ok(Event::Joined(id, desc, wr)) => {
+                    println!("client {} connected ({})", id, desc);
+                    router.joined(id, wr);
+                }
+                Ok(Event::Request(id, msg)) => router.request(id, msg),
+                Ok(Event::Gone(id)) => {
+                    println!("client {} disconnected", id);
+                    router.gone(id);
+                }
+                // Every client listener has failed.
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::BrokenPipe,
+                        "client listeners closed",
+                    ))
+                }
+            },
+            recv(ticks) -> _ => router.expire(Instant::now()),
+        }
+        router.send_next(&mut fc)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use msptest::sim::{Persona, Simulator};
+    use std::sync::Mutex;
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Shared {
+        // Commands of the frames received.
+        fn cmds(&self) -> Vec<u16> {
+            let buf = std::mem::take(&mut *self.0.lock().unwrap());
+            MspParser::new().feed(&buf).map(|m| m.cmd).collect()
+        }
+    }
+
+    fn parse(buf: &[u8]) -> Vec<MSPMsg> {
+        MspParser::new().feed(buf).collect()
+    }
+
+    fn request(cmd: u16) -> MSPMsg {
+        parse(&msp::encode_msp(cmd, &[]).unwrap()).remove(0)
+    }
+
+    // Two clients (1 and 2) and the simulated FC's replies to what the
+    // router sends it.
+    struct Setup {
+        router: Router,
+        clients: [Shared; 2],
+        sim: Simulator,
+        fc: Vec<u8>,
+    }
+
+    impl Setup {
+        fn new() -> Self {
+            let mut router = Router::default();
+            let clients: [Shared; 2] = Default::default();
+            for (id, c) in clients.iter().enumerate() {
+                router.joined(id + 1, Box::new(c.clone()));
+            }
+            Self {
+                router,
+                clients,
+                sim: Simulator::new(Persona::Inav19),
+                fc: Vec::new(),
+            }
+        }
+
+        // Sends what is queued, returning the FC's replies.
+        fn send(&mut self) -> Vec<MSPMsg> {
+            self.router.send_next(&mut self.fc).unwrap();
+            let reqs = parse(&std::mem::take(&mut self.fc));
+            let replies: Vec<u8> = reqs
+                .iter()
+                .flat_map(|r| self.sim.respond(r).unwrap())
+                .collect();
+            parse(&replies)
+        }
+
+        fn cmds(&self) -> [Vec<u16>; 2] {
+            [self.clients[0].cmds(), self.clients[1].cmds()]
+        }
+    }
+
+    #[test]
+    fn replies_go_to_the_requester() {
+        let mut s = Setup::new();
+        s.router.request(1, request(msp::MSG_IDENT));
+        s.router.request(2, request(msp::MSG_NAME));
+        for _ in 0..2 {
+            let r = s.send();
+            assert_eq!(r.len(), 1);
+            s.router.reply(&r[0]);
+        }
+        assert_eq!(s.cmds(), [vec![msp::MSG_IDENT], vec![msp::MSG_NAME]]);
+    }
+
+    #[test]
+    fn late_replies_keep_their_order() {
+        let mut s = Setup::new();
+        // Both poll the same command; the first reply is late.
+        s.router.request(1, request(msp::MSG_STATUS_EX));
+        s.router.request(2, request(msp::MSG_STATUS_EX));
+        let r1 = s.send();
+        s.router.expire(Instant::now() + REPLY_TIMEOUT * 2);
+        let r2 = s.send();
+        s.router.reply(&r1[0]);
+        assert_eq!(s.cmds(), [vec![msp::MSG_STATUS_EX], vec![]]);
+        s.router.reply(&r2[0]);
+        assert_eq!(s.cmds(), [vec![], vec![msp::MSG_STATUS_EX]]);
+
+        // A late reply to another command isn't broadcast.
+        s.router.request(1, request(msp::MSG_IDENT));
+        s.router.request(2, request(msp::MSG_NAME));
+        let r1 = s.send();
+        s.router.expire(Instant::now() + REPLY_TIMEOUT * 2);
+        let r2 = s.send();
+        s.router.reply(&r1[0]);
+        s.router.reply(&r2[0]);
+        assert_eq!(s.cmds(), [vec![msp::MSG_IDENT], vec![msp::MSG_NAME]]);
+    }
+
+    #[test]
+    fn lost_reply_is_skipped() {
+        let mut s = Setup::new();
+        s.router.request(1, request(msp::MSG_IDENT));
+        s.router.request(2, request(msp::MSG_NAME));
+        _ = s.send();
+        s.router.expire(Instant::now() + REPLY_TIMEOUT * 2);
+        let r2 = s.send();
+        s.router.reply(&r2[0]);
+        // Nothing is waiting for the late IDENT reply now.
+        s.router
+            .reply(&parse(&msp::encode_response(1, msp::MSG_IDENT, &[0; 7], false).unwrap())[0]);
+        assert_eq!(s.cmds(), [vec![], vec![msp::MSG_NAME]]);
+    }
+
+    #[test]
+    fn only_unsolicited_frames_are_broadcast() {
+        let mut s = Setup::new();
+        let debug = msp::encode_response(1, msp::MSG_DEBUGMSG, b"hello\0", false).unwrap();
+        s.router.reply(&parse(&debug)[0]);
+        let stray = msp::encode_response(1, msp::MSG_ANALOG, &[0; 7], false).unwrap();
+        s.router.reply(&parse(&stray)[0]);
+        assert_eq!(s.cmds(), [vec![msp::MSG_DEBUGMSG], vec![msp::MSG_DEBUGMSG]]);
+    }
+}
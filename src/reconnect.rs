@@ -0,0 +1,61 @@
+//! Reconnection policy.
+
+use std::time::Duration;
+
+/// Exponential backoff between reconnection attempts.
+///
+/// ```
+/// use std::time::Duration;
+/// use msptest::reconnect::Backoff;
+///
+/// let mut b = Backoff::new(Duration::from_millis(100), Duration::from_millis(350));
+/// assert_eq!(b.next_delay(), Duration::from_millis(100));
+/// assert_eq!(b.next_delay(), Duration::from_millis(200));
+/// assert_eq!(b.next_delay(), Duration::from_millis(350));
+/// assert_eq!(b.attempt(), 3);
+/// b.reset();
+/// assert_eq!(b.next_delay(), Duration::from_millis(100));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Default for Backoff {
+    /// 250ms, doubling to at most 10s.
+    fn default() -> Self {
+        Self::new(Duration::from_millis(250), Duration::from_secs(10))
+    }
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// Number of failed attempts since the last reset.
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Records a failed attempt and returns the delay before the next one.
+    pub fn next_delay(&mut self) -> Duration {
+        let d = self
+            .initial
+            .checked_mul(1 << self.attempt.min(16))
+            .map_or(self.max, |d| d.min(self.max));
+        self.attempt += 1;
+        d
+    }
+
+    /// Resets the delay after a successful connection.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
@@ -33,6 +33,7 @@
 #include <sys/types.h>
 #include <sys/stat.h>
 #include <termios.h>
+#include <poll.h>
 #include <sys/socket.h>
 #include <netinet/in.h>
 #include <netdb.h>
@@ -98,7 +99,41 @@ int set_fd_speed(int fd, int rate) {
   return res;
 }
 
-int open_serial(const char *device, int baudrate) {
+void set_line_options(struct termios *tio, int databits, int parity, int stopbits, int flow) {
+  tio->c_cflag &= ~CSIZE;
+  switch(databits) {
+  case 5: tio->c_cflag |= CS5; break;
+  case 6: tio->c_cflag |= CS6; break;
+  case 7: tio->c_cflag |= CS7; break;
+  default: tio->c_cflag |= CS8; break;
+  }
+  switch(parity) {
+  case 1: tio->c_cflag |= (PARENB|PARODD); break;
+  case 2: tio->c_cflag |= PARENB; tio->c_cflag &= ~PARODD; break;
+  default: tio->c_cflag &= ~(PARENB|PARODD); break;
+  }
+  if(stopbits == 2) {
+    tio->c_cflag |= CSTOPB;
+  } else {
+    tio->c_cflag &= ~CSTOPB;
+  }
+#ifdef CRTSCTS
+  tio->c_cflag &= ~CRTSCTS;
+#endif
+  tio->c_iflag &= ~(IXON|IXOFF|IXANY);
+  switch(flow) {
+  case 1:
+#ifdef CRTSCTS
+    tio->c_cflag |= CRTSCTS;
+#endif
+    break;
+  case 2:
+    tio->c_iflag |= (IXON|IXOFF);
+    break;
+  }
+}
+
+int open_serial_opts(const char *device, int baudrate, int databits, int parity, int stopbits, int flow) {
     int fd;
     fd = open(device, O_RDWR|O_NOCTTY);
     if(fd != -1) {
@@ -106,6 +141,7 @@ int open_serial(const char *device, int baudrate) {
       memset (&tio, 0, sizeof(tio));
       tcgetattr(fd, &tio);
       cfmakeraw(&tio);
+      set_line_options(&tio, databits, parity, stopbits, flow);
       tio.c_cc[VTIME] = 0;
       tio.c_cc[VMIN] = 1;
       tcsetattr(fd,TCSANOW,&tio);
@@ -117,13 +153,8 @@ int open_serial(const char *device, int baudrate) {
     return fd;
 }
 
-void set_timeout(int fd, int tenths, int number) {
-    struct termios tio;
-    memset (&tio, 0, sizeof(tio));
-    tcgetattr(fd, &tio);
-    tio.c_cc[VTIME] = tenths;
-    tio.c_cc[VMIN] = number;
-    tcsetattr(fd,TCSANOW,&tio);
+int open_serial(const char *device, int baudrate) {
+  return open_serial_opts(device, baudrate, 8, 0, 1, 0);
 }
 
 void close_serial(int fd) {
@@ -152,6 +183,30 @@ ssize_t read_serial(int fd, uint8_t*buffer, size_t buflen) {
   return read(fd, buffer, buflen);
 }
 
+/*
+ * Waits up to timeout_ms (-1 for ever) for input, then reads.
+ * Returns the read() result, -2 on timeout or -3 if cancelfd
+ * became readable (cancelled).
+ */
+ssize_t read_serial_wait(int fd, int cancelfd, uint8_t*buffer, size_t buflen, int timeout_ms) {
+  struct pollfd pfd[2];
+  int res;
+  pfd[0].fd = fd;
+  pfd[0].events = POLLIN;
+  pfd[1].fd = cancelfd;
+  pfd[1].events = POLLIN;
+  do {
+    res = poll(pfd, 2, timeout_ms);
+  } while (res == -1 && errno == EINTR);
+  if (res == -1)
+    return -1;
+  if (res == 0)
+    return -2;
+  if (pfd[1].revents != 0)
+    return -3;
+  return read(fd, buffer, buflen);
+}
+
 ssize_t write_serial(int fd, uint8_t*buffer, size_t buflen) {
   return write(fd, buffer, buflen);
 }
@@ -208,7 +263,7 @@ void set_fd_speed(HANDLE hfd, int baudrate) {
     }
 }
 
-void set_timeout(HANDLE hfd, __attribute__ ((unused)) int p0, __attribute__ ((unused)) int p1) {
+static void set_timeout(HANDLE hfd, __attribute__ ((unused)) int p0, __attribute__ ((unused)) int p1) {
   COMMTIMEOUTS ctout;
   GetCommTimeouts(hfd, &ctout);
   ctout.ReadIntervalTimeout = MAXDWORD;
@@ -217,7 +272,26 @@ void set_timeout(HANDLE hfd, __attribute__ ((unused)) int p0, __attribute__ ((un
   SetCommTimeouts(hfd, &ctout);
 }
 
-HANDLE open_serial(const char *device, int baudrate) {
+static void set_line_options(HANDLE hfd, int databits, int parity, int stopbits, int flow) {
+    DCB dcb = {0};
+    dcb.DCBlength = sizeof(DCB);
+    if (GetCommState(hfd, &dcb)) {
+        dcb.ByteSize = (databits >= 5 && databits <= 8) ? databits : 8;
+        switch (parity) {
+            case 1: dcb.Parity = ODDPARITY; break;
+            case 2: dcb.Parity = EVENPARITY; break;
+            default: dcb.Parity = NOPARITY; break;
+        }
+        dcb.fParity = (parity != 0);
+        dcb.StopBits = (stopbits == 2) ? TWOSTOPBITS : ONESTOPBIT;
+        dcb.fOutxCtsFlow = (flow == 1);
+        dcb.fRtsControl = (flow == 1) ? RTS_CONTROL_HANDSHAKE : RTS_CONTROL_ENABLE;
+        dcb.fOutX = dcb.fInX = (flow == 2);
+        SetCommState(hfd, &dcb);
+    }
+}
+
+HANDLE open_serial_opts(const char *device, int baudrate, int databits, int parity, int stopbits, int flow) {
   HANDLE hfd = CreateFile(device,
                    GENERIC_READ|GENERIC_WRITE,
                    0,
@@ -229,10 +303,15 @@ HANDLE open_serial(const char *device, int baudrate) {
   if(hfd != INVALID_HANDLE_VALUE) {
     set_timeout(hfd, 0, 0);
     set_fd_speed(hfd, baudrate);
+    set_line_options(hfd, databits, parity, stopbits, flow);
   }
   return hfd;
 }
 
+HANDLE open_serial(const char *device, int baudrate) {
+  return open_serial_opts(device, baudrate, 8, 0, 1, 0);
+}
+
 void close_serial(HANDLE hfd) {
   CloseHandle(hfd);
 }
@@ -254,6 +333,42 @@ ssize_t read_serial(HANDLE hfd, uint8_t*buffer, size_t buflen) {
   return (ssize_t)nb;
 }
 
+/*
+ * As read_serial, but waits at most timeout_ms (-1 for ever).
+ * Returns -2 on timeout or -3 if the cancel event was signalled.
+ */
+ssize_t read_serial_wait(HANDLE hfd, HANDLE cancel, uint8_t*buffer, size_t buflen, int timeout_ms) {
+  DWORD nb = 0;
+  ssize_t res;
+  OVERLAPPED ovl={0};
+  ovl.hEvent = CreateEvent(NULL, true, false, NULL);
+  if (ReadFile (hfd, buffer, buflen, &nb, &ovl) != 0) {
+    res = (ssize_t)nb;
+  } else if (GetLastError() != ERROR_IO_PENDING) {
+    res = -1;
+  } else {
+    HANDLE evs[2] = {ovl.hEvent, cancel};
+    DWORD w = WaitForMultipleObjects(2, evs, false,
+                                     (timeout_ms < 0) ? INFINITE : (DWORD)timeout_ms);
+    if (w != WAIT_OBJECT_0) {
+      CancelIo(hfd);
+    }
+    if (GetOverlappedResult(hfd, &ovl, &nb, true) && nb > 0) {
+      res = (ssize_t)nb;
+    } else if (w == WAIT_OBJECT_0 + 1) {
+      res = -3;
+    } else if (w == WAIT_TIMEOUT) {
+      res = -2;
+    } else if (w == WAIT_OBJECT_0) {
+      res = 0;
+    } else {
+      res = -1;
+    }
+  }
+  CloseHandle(ovl.hEvent);
+  return res;
+}
+
 ssize_t write_serial(HANDLE hfd, uint8_t*buffer, size_t buflen) {
   DWORD nb= 0;
   OVERLAPPED ovl={0};
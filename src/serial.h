@@ -5,17 +5,21 @@
 
 #if !defined( WIN32 )
 extern int open_serial(const char * name, int baudrate);
+extern int open_serial_opts(const char * name, int baudrate, int databits, int parity, int stopbits, int flow);
 extern ssize_t read_serial(int fd, uint8_t *buf, size_t buflen);
+extern ssize_t read_serial_wait(int fd, int cancelfd, uint8_t *buf, size_t buflen, int timeout_ms);
 extern ssize_t write_serial(int fd, uint8_t *buf, size_t buflen);
-extern void set_timeout(int fd, int tenths, int number);
 extern void close_serial(int fd);
+struct termios;
+extern void set_line_options(struct termios *tio, int databits, int parity, int stopbits, int flow);
 extern void flush_serial(int fd);
 #else
 #include <windows.h>
 extern HANDLE open_serial(const char * name, int baudrate);
+extern HANDLE open_serial_opts(const char * name, int baudrate, int databits, int parity, int stopbits, int flow);
 extern ssize_t read_serial(HANDLE fd, uint8_t *buf, size_t buflen);
+extern ssize_t read_serial_wait(HANDLE fd, HANDLE cancel, uint8_t *buf, size_t buflen, int timeout_ms);
 extern ssize_t write_serial(HANDLE fd, uint8_t *buf, size_t buflen);
-extern void set_timeout(HANDLE fd, int tenths, int number);
 extern void close_serial(HANDLE fd);
 extern void flush_serial(HANDLE fd);
 #endif
@@ -0,0 +1,59 @@
+//! Serial line settings (data bits, parity, stop bits, flow control).
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Parity {
+    #[default]
+    None,
+    Odd,
+    Even,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlowControl {
+    #[default]
+    None,
+    /// Hardware (RTS/CTS) flow control.
+    RtsCts,
+    /// Software (XON/XOFF) flow control.
+    XonXoff,
+}
+
+/// Serial line settings; the default is 8N1 without flow control.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialOptions {
+    /// 5 to 8.
+    pub data_bits: u8,
+    pub parity: Parity,
+    /// 1 or 2.
+    pub stop_bits: u8,
+    pub flow: FlowControl,
+}
+
+impl Default for SerialOptions {
+    fn default() -> Self {
+        Self {
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: 1,
+            flow: FlowControl::None,
+        }
+    }
+}
+
+impl fmt::Display for SerialOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let p = match self.parity {
+            Parity::None => 'N',
+            Parity::Odd => 'O',
+            Parity::Even => 'E',
+        };
+        write!(f, "{}{}{}", self.data_bits, p, self.stop_bits)?;
+        match self.flow {
+            FlowControl::None => Ok(()),
+            FlowControl::RtsCts => write!(f, " rtscts"),
+            FlowControl::XonXoff => write!(f, " xonxoff"),
+        }
+    }
+}
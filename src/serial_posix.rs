@@ -2,79 +2,223 @@ use libc::{c_char, c_int, size_t, ssize_t};
 use std::ffi::CString;
 use std::io;
 use std::io::Error;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub use crate::serial_options::{FlowControl, Parity, SerialOptions};
 
 #[link(name = "serial")]
 extern "C" {
-    fn open_serial(name: *const c_char, baud: c_int) -> c_int;
-    fn read_serial(fd: c_int, buf: *mut u8, buflen: size_t) -> ssize_t;
+    fn open_serial_opts(
+        name: *const c_char,
+        baud: c_int,
+        databits: c_int,
+        parity: c_int,
+        stopbits: c_int,
+        flow: c_int,
+    ) -> c_int;
+    fn read_serial_wait(
+        fd: c_int,
+        cancelfd: c_int,
+        buf: *mut u8,
+        buflen: size_t,
+        timeout_ms: c_int,
+    ) -> ssize_t;
     fn write_serial(fd: c_int, buf: *const u8, buflen: size_t) -> ssize_t;
     fn close_serial(fd: c_int);
     fn flush_serial(fd: c_int);
 }
 
+// Open device, shared by clones. The pipe is used to wake blocked readers
+// on close; the device itself is closed when the last clone is dropped.
+#[derive(Debug)]
+struct Handle {
+    fd: c_int,
+    cancel: [c_int; 2],
+    closed: AtomicBool,
+}
+
+impl Handle {
+    fn cancel(&self) {
+        if !self.closed.swap(true, Ordering::SeqCst) {
+            unsafe {
+                libc::write(self.cancel[1], b"x".as_ptr() as *const libc::c_void, 1);
+            }
+        }
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        unsafe {
+            close_serial(self.fd);
+            libc::close(self.cancel[0]);
+            libc::close(self.cancel[1]);
+        }
+    }
+}
+
+/// Serial port using the embedded C implementation.
+///
+/// Clones share the underlying OS handle, so one clone may be used for
+/// reading in a separate thread while another is used for writing. The
+/// handle is released when the last clone is dropped.
 #[derive(Debug, Clone)]
 pub struct SerialDevice {
-    pub fd: c_int,
+    handle: Option<Arc<Handle>>,
+    timeout: Arc<AtomicI32>,
 }
 
-unsafe impl Sync for SerialDevice {}
+impl Default for SerialDevice {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn not_open() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "serial device not open")
+}
 
 impl SerialDevice {
     pub fn new() -> Self {
-        Self { fd: -1 }
+        Self {
+            handle: None,
+            timeout: Arc::new(AtomicI32::new(-1)),
+        }
     }
 
+    /// Opens `dname` in raw mode at `baud`, 8N1 without flow control.
     pub fn open(&mut self, dname: &str, baud: isize) -> io::Result<()> {
+        self.open_with(dname, baud, &SerialOptions::default())
+    }
+
+    /// Opens `dname` in raw mode at `baud` with the given line settings.
+    pub fn open_with(&mut self, dname: &str, baud: isize, opts: &SerialOptions) -> io::Result<()> {
         let dptr = CString::new(dname.to_string()).unwrap();
+        let parity = match opts.parity {
+            Parity::None => 0,
+            Parity::Odd => 1,
+            Parity::Even => 2,
+        };
+        let flow = match opts.flow {
+            FlowControl::None => 0,
+            FlowControl::RtsCts => 1,
+            FlowControl::XonXoff => 2,
+        };
+        self.close();
         unsafe {
-            self.fd = open_serial(dptr.as_ptr(), baud as c_int);
-            if self.fd < 0 {
-                Err(Error::last_os_error())
+            let mut cancel = [-1; 2];
+            if libc::pipe(cancel.as_mut_ptr()) != 0 {
+                return Err(Error::last_os_error());
+            }
+            let fd = open_serial_opts(
+                dptr.as_ptr(),
+                baud as c_int,
+                opts.data_bits as c_int,
+                parity,
+                opts.stop_bits as c_int,
+                flow,
+            );
+            if fd < 0 {
+                let e = Error::last_os_error();
+                libc::close(cancel[0]);
+                libc::close(cancel[1]);
+                Err(e)
             } else {
+                self.handle = Some(Arc::new(Handle {
+                    fd,
+                    cancel,
+                    closed: AtomicBool::new(false),
+                }));
                 Ok(())
             }
         }
     }
 
+    /// Sets the read timeout, shared by all clones; `None` (the default)
+    /// blocks until data arrives. A read that times out returns
+    /// [`io::ErrorKind::TimedOut`].
+    pub fn set_timeout(&self, timeout: Option<Duration>) {
+        let ms = match timeout {
+            Some(t) => t.as_millis().min(i32::MAX as u128) as i32,
+            None => -1,
+        };
+        self.timeout.store(ms, Ordering::Relaxed);
+    }
+
+    /// Closes the device. A read blocked in another clone returns `Ok(0)`
+    /// (end of file), and subsequent reads and writes on any clone fail.
     pub fn close(&mut self) {
-        if self.fd != -1 {
+        if let Some(h) = self.handle.take() {
+            h.cancel();
+        }
+    }
+
+    /// Discards any pending input and output.
+    pub fn clear(&self) {
+        if let Some(ref h) = self.handle {
             unsafe {
-                close_serial(self.fd);
+                flush_serial(h.fd);
             }
-            self.fd = -1;
         }
     }
 
-    pub fn clear(&self) {
-        unsafe {
-            flush_serial(self.fd);
+    fn open_handle(&self) -> io::Result<&Handle> {
+        match self.handle {
+            Some(ref h) if !h.closed.load(Ordering::SeqCst) => Ok(h),
+            _ => Err(not_open()),
         }
     }
 }
 
+impl AsRawFd for SerialDevice {
+    /// Returns the device file descriptor, or -1 if not open.
+    fn as_raw_fd(&self) -> RawFd {
+        self.handle.as_ref().map_or(-1, |h| h.fd)
+    }
+}
+
 impl io::Read for SerialDevice {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let h = match self.handle {
+            Some(ref h) if h.closed.load(Ordering::SeqCst) => return Ok(0),
+            Some(ref h) => h,
+            None => return Err(not_open()),
+        };
         let n: ssize_t;
         unsafe {
-            n = read_serial(self.fd, buf.as_mut_ptr(), buf.len() as size_t);
+            n = read_serial_wait(
+                h.fd,
+                h.cancel[0],
+                buf.as_mut_ptr(),
+                buf.len() as size_t,
+                self.timeout.load(Ordering::Relaxed),
+            );
         }
-        if n <= 0 {
-            Err(io::Error::last_os_error())
-        } else {
-            Ok(n as usize)
+        match n {
+            -2 => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "serial read timed out",
+            )),
+            -3 => Ok(0),
+            n if n < 0 => Err(io::Error::last_os_error()),
+            n => Ok(n as usize),
         }
     }
 }
 
 impl io::Write for SerialDevice {
     fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+        let fd = self.open_handle()?.fd;
         let n: ssize_t;
         unsafe {
-            n = write_serial(self.fd, src.as_ptr(), src.len());
+            n = write_serial(fd, src.as_ptr(), src.len());
         }
-	if n <= 0 {
+        if n <= 0 {
             Err(io::Error::last_os_error())
-	} else {
+        } else {
             Ok(n as usize)
         }
     }
@@ -84,8 +228,128 @@ impl io::Write for SerialDevice {
     }
 }
 
-impl Drop for SerialDevice {
-    fn drop(&mut self) {
-        self.close();
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use libc::{tcflag_t, termios};
+    use std::ffi::CStr;
+
+    #[link(name = "serial")]
+    extern "C" {
+        fn set_line_options(
+            tio: *mut termios,
+            databits: c_int,
+            parity: c_int,
+            stopbits: c_int,
+            flow: c_int,
+        );
+    }
+
+    const CMASK: tcflag_t =
+        libc::CSIZE | libc::PARENB | libc::PARODD | libc::CSTOPB | libc::CRTSCTS;
+    const IMASK: tcflag_t = libc::IXON | libc::IXOFF;
+
+    // Options with the expected c_cflag and c_iflag bits.
+    fn cases() -> Vec<(SerialOptions, tcflag_t, tcflag_t)> {
+        let o = |data_bits, parity, stop_bits, flow| SerialOptions {
+            data_bits,
+            parity,
+            stop_bits,
+            flow,
+        };
+        vec![
+            (SerialOptions::default(), libc::CS8, 0),
+            (
+                o(7, Parity::Even, 1, FlowControl::None),
+                libc::CS7 | libc::PARENB,
+                0,
+            ),
+            (
+                o(5, Parity::Odd, 1, FlowControl::None),
+                libc::CS5 | libc::PARENB | libc::PARODD,
+                0,
+            ),
+            (
+                o(6, Parity::None, 2, FlowControl::None),
+                libc::CS6 | libc::CSTOPB,
+                0,
+            ),
+            (
+                o(8, Parity::Odd, 2, FlowControl::RtsCts),
+                libc::CS8 | libc::PARENB | libc::PARODD | libc::CSTOPB | libc::CRTSCTS,
+                0,
+            ),
+            (
+                o(8, Parity::None, 1, FlowControl::XonXoff),
+                libc::CS8,
+                libc::IXON | libc::IXOFF,
+            ),
+        ]
+    }
+
+    fn termios_of(fd: RawFd) -> termios {
+        unsafe {
+            let mut tio: termios = std::mem::zeroed();
+            assert_eq!(libc::tcgetattr(fd, &mut tio), 0);
+            tio
+        }
+    }
+
+    #[test]
+    fn line_flags() {
+        for (opts, cflag, iflag) in cases() {
+            let parity = match opts.parity {
+                Parity::None => 0,
+                Parity::Odd => 1,
+                Parity::Even => 2,
+            };
+            let flow = match opts.flow {
+                FlowControl::None => 0,
+                FlowControl::RtsCts => 1,
+                FlowControl::XonXoff => 2,
+            };
+            // Start with every flag set, to check they are cleared.
+            let mut tio: termios = unsafe { std::mem::zeroed() };
+            tio.c_cflag = CMASK;
+            tio.c_iflag = IMASK | libc::IXANY;
+            unsafe {
+                set_line_options(
+                    &mut tio,
+                    opts.data_bits as c_int,
+                    parity,
+                    opts.stop_bits as c_int,
+                    flow,
+                );
+            }
+            assert_eq!(tio.c_cflag & CMASK, cflag, "{}", opts);
+            assert_eq!(tio.c_iflag & (IMASK | libc::IXANY), iflag, "{}", opts);
+        }
+    }
+
+    #[test]
+    fn pty_line_options() {
+        let (master, name) = unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            assert!(fd >= 0 && libc::grantpt(fd) == 0 && libc::unlockpt(fd) == 0);
+            let name = CStr::from_ptr(libc::ptsname(fd))
+                .to_string_lossy()
+                .into_owned();
+            (fd, name)
+        };
+        let mut sd = SerialDevice::new();
+        for (opts, cflag, iflag) in cases() {
+            sd.open_with(&name, 115200, &opts).unwrap();
+            let tio = termios_of(sd.as_raw_fd());
+            // The pty driver forces CS8 and clears PARENB; the other flags
+            // are as set.
+            let pty = libc::CSIZE | libc::PARENB;
+            assert_eq!(tio.c_cflag & pty, libc::CS8, "{}", opts);
+            assert_eq!(tio.c_cflag & CMASK & !pty, cflag & !pty, "{}", opts);
+            assert_eq!(tio.c_iflag & IMASK, iflag, "{}", opts);
+            sd.close();
+        }
+        unsafe {
+            libc::close(master);
+        }
     }
 }
@@ -3,77 +3,214 @@ use std::io;
 use std::io::Error;
 
 use std::ffi::CString;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use winapi::um::handleapi::*;
+use winapi::um::synchapi::{CreateEventW, SetEvent};
 use winapi::um::winnt::HANDLE;
 
+pub use crate::serial_options::{FlowControl, Parity, SerialOptions};
+
 #[link(name = "serial")]
 extern "C" {
-    fn open_serial(name: *const c_char, baud: c_int) -> HANDLE;
-    fn read_serial(hfd: HANDLE, buf: *mut u8, buflen: size_t) -> ssize_t;
+    fn open_serial_opts(
+        name: *const c_char,
+        baud: c_int,
+        databits: c_int,
+        parity: c_int,
+        stopbits: c_int,
+        flow: c_int,
+    ) -> HANDLE;
+    fn read_serial_wait(
+        hfd: HANDLE,
+        cancel: HANDLE,
+        buf: *mut u8,
+        buflen: size_t,
+        timeout_ms: c_int,
+    ) -> ssize_t;
     fn write_serial(hfd: HANDLE, buf: *const u8, buflen: size_t) -> ssize_t;
     fn close_serial(hfd: HANDLE);
     fn flush_serial(hfd: HANDLE);
 }
 
+// Open device, shared by clones. The event is used to wake blocked readers
+// on close; the device itself is closed when the last clone is dropped.
+#[derive(Debug)]
+struct Handle {
+    hfd: HANDLE,
+    cancel: HANDLE,
+    closed: AtomicBool,
+}
+
+unsafe impl Send for Handle {}
+unsafe impl Sync for Handle {}
+
+impl Handle {
+    fn cancel(&self) {
+        if !self.closed.swap(true, Ordering::SeqCst) {
+            unsafe {
+                SetEvent(self.cancel);
+            }
+        }
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        unsafe {
+            close_serial(self.hfd);
+            CloseHandle(self.cancel);
+        }
+    }
+}
+
+/// Serial port using the embedded C implementation.
+///
+/// Clones share the underlying OS handle, so one clone may be used for
+/// reading in a separate thread while another is used for writing. The
+/// handle is released when the last clone is dropped.
 #[derive(Debug, Clone)]
 pub struct SerialDevice {
-    hfd: HANDLE,
+    handle: Option<Arc<Handle>>,
+    timeout: Arc<AtomicI32>,
+}
+
+impl Default for SerialDevice {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-unsafe impl Send for SerialDevice {}
+fn not_open() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "serial device not open")
+}
 
 impl SerialDevice {
     pub fn new() -> Self {
         Self {
-            hfd: INVALID_HANDLE_VALUE,
+            handle: None,
+            timeout: Arc::new(AtomicI32::new(-1)),
         }
     }
 
+    /// Opens `dname` in raw mode at `baud`, 8N1 without flow control.
     pub fn open(&mut self, dname: &str, baud: isize) -> io::Result<()> {
+        self.open_with(dname, baud, &SerialOptions::default())
+    }
+
+    /// Opens `dname` in raw mode at `baud` with the given line settings.
+    pub fn open_with(&mut self, dname: &str, baud: isize, opts: &SerialOptions) -> io::Result<()> {
         let dptr = CString::new(dname.to_string()).unwrap();
+        let parity = match opts.parity {
+            Parity::None => 0,
+            Parity::Odd => 1,
+            Parity::Even => 2,
+        };
+        let flow = match opts.flow {
+            FlowControl::None => 0,
+            FlowControl::RtsCts => 1,
+            FlowControl::XonXoff => 2,
+        };
+        self.close();
         unsafe {
-            self.hfd = open_serial(dptr.as_ptr(), baud as c_int);
-            if self.hfd == INVALID_HANDLE_VALUE {
-                Err(Error::last_os_error())
+            let cancel = CreateEventW(ptr::null_mut(), 1, 0, ptr::null());
+            if cancel.is_null() {
+                return Err(Error::last_os_error());
+            }
+            let hfd = open_serial_opts(
+                dptr.as_ptr(),
+                baud as c_int,
+                opts.data_bits as c_int,
+                parity,
+                opts.stop_bits as c_int,
+                flow,
+            );
+            if hfd == INVALID_HANDLE_VALUE {
+                let e = Error::last_os_error();
+                CloseHandle(cancel);
+                Err(e)
             } else {
+                self.handle = Some(Arc::new(Handle {
+                    hfd,
+                    cancel,
+                    closed: AtomicBool::new(false),
+                }));
                 Ok(())
             }
         }
     }
 
+    /// Sets the read timeout, shared by all clones; `None` (the default)
+    /// blocks until data arrives. A read that times out returns
+    /// [`io::ErrorKind::TimedOut`].
+    pub fn set_timeout(&self, timeout: Option<Duration>) {
+        let ms = match timeout {
+            Some(t) => t.as_millis().min(i32::MAX as u128) as i32,
+            None => -1,
+        };
+        self.timeout.store(ms, Ordering::Relaxed);
+    }
+
+    /// Discards any pending input and output.
     pub fn clear(&self) {
-        unsafe { flush_serial(self.hfd) }
+        if let Some(ref h) = self.handle {
+            unsafe { flush_serial(h.hfd) }
+        }
     }
 
+    /// Closes the device. A read blocked in another clone returns `Ok(0)`
+    /// (end of file), and subsequent reads and writes on any clone fail.
     pub fn close(&mut self) {
-        if self.hfd != INVALID_HANDLE_VALUE {
-            unsafe {
-                close_serial(self.hfd);
-            }
-            self.hfd = INVALID_HANDLE_VALUE;
+        if let Some(h) = self.handle.take() {
+            h.cancel();
+        }
+    }
+
+    fn open_handle(&self) -> io::Result<&Handle> {
+        match self.handle {
+            Some(ref h) if !h.closed.load(Ordering::SeqCst) => Ok(h),
+            _ => Err(not_open()),
         }
     }
 }
 
 impl io::Read for SerialDevice {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let h = match self.handle {
+            Some(ref h) if h.closed.load(Ordering::SeqCst) => return Ok(0),
+            Some(ref h) => h,
+            None => return Err(not_open()),
+        };
         let n: ssize_t;
         unsafe {
-            n = read_serial(self.hfd, buf.as_mut_ptr(), buf.len() as size_t);
+            n = read_serial_wait(
+                h.hfd,
+                h.cancel,
+                buf.as_mut_ptr(),
+                buf.len() as size_t,
+                self.timeout.load(Ordering::Relaxed),
+            );
         }
-        if n == 0 {
-            Err(io::Error::last_os_error())
-        } else {
-            Ok(n as usize)
+        match n {
+            -2 => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "serial read timed out",
+            )),
+            -3 | 0 => Ok(0),
+            n if n < 0 => Err(io::Error::last_os_error()),
+            n => Ok(n as usize),
         }
     }
 }
 
 impl io::Write for SerialDevice {
     fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+        let hfd = self.open_handle()?.hfd;
         let n: ssize_t;
         unsafe {
-            n = write_serial(self.hfd, src.as_ptr(), src.len());
+            n = write_serial(hfd, src.as_ptr(), src.len());
         }
         match n {
             0 => Err(io::Error::last_os_error()),
@@ -85,9 +222,3 @@ impl io::Write for SerialDevice {
         Ok(())
     }
 }
-
-impl Drop for SerialDevice {
-    fn drop(&mut self) {
-        self.close()
-    }
-}
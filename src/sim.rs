@@ -0,0 +1,284 @@
+//! Simulated flight controller (MSP responder), for testing clients
+//! without hardware or the SITL.
+//!
+//! A [`Simulator`] answers the commands used by the viewer as a given
+//! [`Persona`] (firmware type and version) would, with a GPS position that
+//! moves around a circle. Commands the persona does not support are
+//! answered with an error (`!`) frame.
+
+use crate::messages::*;
+use crate::msp::{self, EncodeError, MSPMsg};
+use std::f64::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Instant;
+
+/// Firmware emulated by a [`Simulator`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Persona {
+    /// Current INAV (MSPv2, no `MSG_IDENT`).
+    #[default]
+    Inav,
+    /// INAV 1.9 (`MSG_IDENT`, MSPv1 status commands only).
+    Inav19,
+    /// MultiWii 2.4 (`MSG_IDENT`, `MSG_RAW_GPS` and `MSG_ANALOG` only).
+    MultiWii,
+    /// Betaflight 4.x (MSP API 1.x, no navigation).
+    Betaflight,
+}
+
+impl Persona {
+    pub const ALL: [Persona; 4] = [
+        Persona::Inav,
+        Persona::Inav19,
+        Persona::MultiWii,
+        Persona::Betaflight,
+    ];
+
+    /// The persona name, as parsed by `from_str`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Persona::Inav => "inav",
+            Persona::Inav19 => "inav19",
+            Persona::MultiWii => "multiwii",
+            Persona::Betaflight => "betaflight",
+        }
+    }
+}
+
+impl fmt::Display for Persona {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Error returned for an unknown [`Persona`] name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PersonaError(String);
+
+impl fmt::Display for PersonaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown persona \"{}\" (expected inav, inav19, multiwii or betaflight)",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for PersonaError {}
+
+impl FromStr for Persona {
+    type Err = PersonaError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase();
+        Persona::ALL
+            .iter()
+            .copied()
+            .find(|p| p.name() == s)
+            .ok_or(PersonaError(s))
+    }
+}
+
+/// Centre of the simulated flight path.
+const HOME: (f64, f64) = (50.9105, -1.5349);
+/// Radius of the simulated flight path, metres.
+const RADIUS: f64 = 200.0;
+/// Simulated ground speed, m/s.
+const SPEED: f64 = 12.0;
+/// Metres per degree of latitude.
+const M_PER_DEG: f64 = 111_320.0;
+
+/// MSP responder emulating a [`Persona`].
+///
+/// ```
+/// use msptest::messages::Message;
+/// use msptest::msp::{self, MSPRes, MspParser};
+/// use msptest::sim::{Persona, Simulator};
+///
+/// let sim = Simulator::new(Persona::MultiWii);
+/// match sim.message(msp::MSG_RAW_GPS, 10.0) {
+///     Some(Message::RawGps(g)) => assert!(g.fix == 2 && g.hdop.is_none()),
+///     m => panic!("{:?}", m),
+/// }
+///
+/// // MultiWii has no craft name.
+/// let mut p = MspParser::new();
+/// let req = p.feed(&msp::encode_msp(msp::MSG_NAME, &[]).unwrap()).next().unwrap();
+/// let reply = sim.respond(&req).unwrap();
+/// let msg = p.feed(&reply).next().unwrap();
+/// assert!(matches!(msg.ok, MSPRes::Error));
+///
+/// // Tunnelled requests are answered tunnelled.
+/// let sim = Simulator::new(Persona::Inav);
+/// let req = msp::encode_msp2_over_v1(msp::MSG_INAV_STATUS, &[]).unwrap();
+/// let req = p.feed(&req).next().unwrap();
+/// let reply = sim.respond(&req).unwrap();
+/// assert!(reply.starts_with(b"$M>") && reply[4] == msp::MSG_V2_FRAME as u8);
+/// let msg = p.feed(&reply).next().unwrap();
+/// assert!(matches!(msg.ok, MSPRes::Ok) && msg.tunnelled);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Simulator {
+    persona: Persona,
+    name: String,
+    start: Instant,
+}
+
+impl Simulator {
+    pub fn new(persona: Persona) -> Self {
+        Self {
+            persona,
+            name: "Simulator".to_string(),
+            start: Instant::now(),
+        }
+    }
+
+    pub fn persona(&self) -> Persona {
+        self.persona
+    }
+
+    /// Sets the craft name (`MSG_NAME`).
+    pub fn set_name(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+
+    /// Returns the response to `cmd` at `t` seconds after power on, or
+    /// `None` if the persona does not support the command.
+    pub fn message(&self, cmd: u16, t: f64) -> Option<Message> {
+        use Persona::*;
+        let p = self.persona;
+        let inav = matches!(p, Inav | Inav19);
+        let m = match Command::from_id(cmd)? {
+            Command::Ident if p != Betaflight && p != Inav => Message::Ident(Ident {
+                version: if p == MultiWii { 240 } else { 190 },
+                multitype: 3,
+                msp_version: 0,
+                capability: 0,
+            }),
+            Command::Name if p != MultiWii => Message::Name(Name {
+                name: self.name.clone(),
+            }),
+            Command::ApiVersion if p != MultiWii => {
+                let (major, minor) = match p {
+                    Inav => (2, 5),
+                    Inav19 => (2, 1),
+                    _ => (1, 46),
+                };
+                Message::ApiVersion(ApiVersion {
+                    protocol: 0,
+                    major,
+                    minor,
+                })
+            }
+            Command::FcVariant if p != MultiWii => Message::FcVariant(FcVariant {
+                variant: if inav { "INAV" } else { "BTFL" }.to_string(),
+            }),
+            Command::FcVersion if p != MultiWii => {
+                let (major, minor, patch) = match p {
+                    Inav => (7, 1, 2),
+                    Inav19 => (1, 9, 1),
+                    _ => (4, 5, 1),
+                };
+                Message::FcVersion(FcVersion {
+                    major,
+                    minor,
+                    patch,
+                })
+            }
+            Command::BuildInfo if p != MultiWii => Message::BuildInfo(BuildInfo {
+                date: "Jan  1 2024".to_string(),
+                time: "12:00:00".to_string(),
+                git_revision: "0123abcd".to_string(),
+            }),
+            Command::BoardInfo if p != MultiWii => Message::BoardInfo(BoardInfo {
+                board_id: if inav { "MF4S" } else { "S405" }.to_string(),
+                hardware_revision: 0,
+                osd_support: 2,
+                comm_capabilities: 0,
+                // INAV 1.9 predates the target name.
+                target_name: match p {
+                    Inav => "MATEKF405",
+                    Betaflight => "STM32F405",
+                    _ => "",
+                }
+                .to_string(),
+            }),
+            Command::WpGetInfo if inav => Message::WpInfo(WpInfo {
+                capabilities: 0,
+                max_waypoints: if p == Inav { 120 } else { 60 },
+                valid: false,
+                count: 0,
+            }),
+            Command::RawGps => Message::RawGps(gps(t, p != MultiWii)),
+            Command::Analog => Message::Analog(Analog {
+                volts: volts(t),
+                mah_drawn: (t * 2.0) as u16,
+                rssi: 1023,
+                amps: 7.2,
+            }),
+            Command::StatusEx if p != MultiWii => Message::StatusEx(StatusEx {
+                cycle_time: 1000,
+                cpu_load: 12,
+                ..Default::default()
+            }),
+            Command::Analog2 if p == Inav => Message::Analog2(Analog2 {
+                battery_flags: 0,
+                volts: volts(t),
+                amps: 7.2,
+            }),
+            Command::InavStatus if p == Inav => Message::InavStatus(InavStatus {
+                cycle_time: 1000,
+                cpu_load: 12,
+                ..Default::default()
+            }),
+            Command::Misc2 if p == Inav => Message::Misc2(Misc2 {
+                uptime: t as u32,
+                flight_time: 0,
+            }),
+            _ => return None,
+        };
+        Some(m)
+    }
+
+    /// Encodes the response frame to the request `req`, in the same framing
+    /// (MSP version, and MSPv2 tunnelled in MSPv1): `>` with the payload for
+    /// the current time, or `!` if the command is not supported.
+    pub fn respond(&self, req: &MSPMsg) -> Result<Vec<u8>, EncodeError> {
+        let t = self.start.elapsed().as_secs_f64();
+        let (payload, error) = match self.message(req.cmd, t) {
+            Some(m) => (m.encode(), false),
+            None => (Vec::new(), true),
+        };
+        if req.tunnelled {
+            msp::encode_response_over_v1(req.cmd, &payload, error)
+        } else {
+            msp::encode_response(req.vers, req.cmd, &payload, error)
+        }
+    }
+}
+
+// Battery slowly discharging from 4S full.
+fn volts(t: f64) -> f32 {
+    (16.8 - t / 600.0).max(13.2) as f32
+}
+
+// Position on a circle around HOME, flown clockwise at SPEED.
+fn gps(t: f64, hdop: bool) -> RawGps {
+    let a = SPEED * t / RADIUS;
+    let lat = HOME.0 + RADIUS * a.cos() / M_PER_DEG;
+    let lon = HOME.1 + RADIUS * a.sin() / (M_PER_DEG * HOME.0.to_radians().cos());
+    // Direction of travel: the tangent to the circle.
+    let course = (a + PI / 2.0).to_degrees().rem_euclid(360.0);
+    RawGps {
+        fix: 2,
+        num_sat: 14,
+        lat,
+        lon,
+        alt: (100.0 + 10.0 * (t / 30.0).sin()) as i16,
+        speed: SPEED as f32,
+        course: course as f32,
+        hdop: hdop.then_some(0.9),
+    }
+}
@@ -0,0 +1,129 @@
+// Simulated FC (--simulate): answers MSP requests as the chosen persona on
+// TCP / UDP listeners or (Unix) a pseudo-terminal, so the viewer and other
+// clients can be tested without hardware or the SITL.
+
+use msptest::msp::{MSPRes, MspParser};
+use msptest::net::UdpServer;
+use msptest::parse_dev;
+use msptest::sim::{Persona, Simulator};
+use msptest::transport::Endpoint;
+use std::io;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+
+/// Listen address when none is given; the SITL's first MSP port.
+const DEFAULT_LISTEN: &str = "tcp-listen://0.0.0.0:5760";
+
+fn serve<R: Read, W: Write>(mut rd: R, mut wr: W, sim: &Simulator) -> io::Result<()> {
+    let mut parser = MspParser::new();
+    let mut inp = [0u8; 256];
+    loop {
+        let n = rd.read(&mut inp)?;
+        if n == 0 {
+            return Ok(());
+        }
+        for msg in parser.feed(&inp[..n]) {
+            if matches!(msg.ok, MSPRes::Request) {
+                if let Ok(buf) = sim.respond(&msg) {
+                    wr.write_all(&buf)?;
+                }
+            }
+        }
+    }
+}
+
+fn tcp_server(host: &str, port: u16, sim: Simulator) -> io::Result<()> {
+    let listener = TcpListener::bind((host, port))?;
+    thread::spawn(move || {
+        for conn in listener.incoming().flatten() {
+            _ = conn.set_nodelay(true);
+            let Ok(wr) = conn.try_clone() else { continue };
+            if let Ok(a) = conn.peer_addr() {
+                println!("client connected (tcp {})", a);
+            }
+            let sim = sim.clone();
+            thread::spawn(move || serve(conn, wr, &sim));
+        }
+    });
+    Ok(())
+}
+
+// Replies go to the sender of the latest datagram.
+fn udp_server(host: &str, port: u16, sim: Simulator) -> io::Result<()> {
+    let srv = UdpServer::bind(host, port)?;
+    thread::spawn(move || -> io::Result<()> {
+        let conn = srv.accept()?;
+        println!("client connected (udp {})", conn.peer_addr()?);
+        let wr = conn.try_clone()?;
+        serve(conn, wr, &sim)
+    });
+    Ok(())
+}
+
+#[cfg(unix)]
+fn pty_server(sim: Simulator) -> io::Result<()> {
+    use std::ffi::CStr;
+    use std::fs::File;
+    use std::os::unix::io::FromRawFd;
+
+    let name = unsafe {
+        let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+        if fd < 0 || libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let name = libc::ptsname(fd);
+        if name.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let name = CStr::from_ptr(name).to_string_lossy().into_owned();
+        let master = File::from_raw_fd(fd);
+        thread::spawn(move || loop {
+            // The master reads fail (EIO) while no client has the device open.
+            let Ok(wr) = master.try_clone() else { return };
+            if serve(&master, wr, &sim).is_err() {
+                thread::sleep(Duration::from_millis(100));
+            }
+        });
+        name
+    };
+    println!("Serial device {}", name);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn pty_server(_sim: Simulator) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "pty is only available on Unix",
+    ))
+}
+
+/// Runs the simulator as `persona` on each of `args` (`tcp-listen://`,
+/// `udp-listen://` or `pty`) until interrupted.
+pub fn run(persona: Persona, args: &[String]) -> io::Result<()> {
+    let sim = Simulator::new(persona);
+    let mut listen: Vec<&str> = args.iter().map(String::as_str).collect();
+    if listen.is_empty() {
+        listen.push(DEFAULT_LISTEN);
+    }
+    println!("Simulating {} on {}", persona, listen.join(", "));
+    for l in &listen {
+        let res = match *l {
+            "pty" => pty_server(sim.clone()),
+            _ => match parse_dev::parse_uri_dev(l)? {
+                Endpoint::TcpListen { host, port } => tcp_server(&host, port, sim.clone()),
+                Endpoint::UdpListen { host, port } => udp_server(&host, port, sim.clone()),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "expected tcp-listen://, udp-listen:// or pty",
+                )),
+            },
+        };
+        res.map_err(|e| io::Error::new(e.kind(), format!("{}: {}", l, e)))?;
+    }
+    loop {
+        thread::park();
+    }
+}
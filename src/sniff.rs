@@ -0,0 +1,99 @@
+// Passive MSP monitor ("sniffer"): decodes and prints frames in both
+// directions without sending anything. Several devices may be given, e.g.
+// two adapters tapping the TX and RX lines of a UART.
+
+use crossbeam_channel::unbounded;
+use msptest::messages::{self, Command};
+use msptest::msp::{MSPMsg, MSPRes, MspParser};
+use msptest::transport::{Endpoint, Transport};
+use msptest::{devices, parse_dev};
+use std::io;
+use std::io::Read;
+use std::thread;
+use std::time::Instant;
+
+fn open(dev: &str) -> io::Result<(Box<dyn Transport>, Box<dyn Read + Send>)> {
+    let ep = match dev {
+        "auto" => Endpoint::Serial {
+            name: devices::get_serial_device(dev, true),
+            baud: 115200,
+            opts: Default::default(),
+        },
+        _ => parse_dev::parse_uri_dev(dev)?,
+    };
+    let mut tp = ep.transport();
+    tp.open()?;
+    let rd = tp.reader()?;
+    Ok((tp, rd))
+}
+
+fn describe(msg: &MSPMsg) -> String {
+    if msg.data.is_empty() {
+        return String::new();
+    }
+    match msg.ok {
+        MSPRes::Ok => match messages::decode(msg.cmd, &msg.data) {
+            Ok(m) => format!(" {:?}", m),
+            Err(messages::DecodeError::Unknown(_)) => hex(&msg.data),
+            Err(e) => format!(" ({}){}", e, hex(&msg.data)),
+        },
+        _ => hex(&msg.data),
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!(" {:02x}", b)).collect()
+}
+
+fn show(et: f64, src: usize, nsrc: usize, msg: &MSPMsg) {
+    let status = match msg.ok {
+        MSPRes::Crc => " CRC",
+        MSPRes::Error => " unsupported",
+        _ => "",
+    };
+    let name = Command::from_id(msg.cmd).map_or("?", Command::name);
+    let tag = if nsrc > 1 {
+        format!(" [{}]", src)
+    } else {
+        String::new()
+    };
+    println!(
+        "{:10.3}{} {} {} ({}) len {}{}{}",
+        et,
+        tag,
+        msg.dirn as char,
+        name,
+        msg.cmd,
+        msg.len,
+        status,
+        describe(msg)
+    );
+}
+
+pub fn run(devs: &[String]) -> io::Result<()> {
+    let (tx, rx) = unbounded();
+    for (i, dev) in devs.iter().enumerate() {
+        let (tp, mut rd) =
+            open(dev).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", dev, e)))?;
+        let tx = tx.clone();
+        thread::spawn(move || {
+            let _tp = tp;
+            let mut parser = MspParser::new();
+            let mut inp = [0u8; 256];
+            while let Ok(n @ 1..) = rd.read(&mut inp) {
+                for msg in parser.feed(&inp[..n]) {
+                    if tx.send((i, Instant::now(), msg)).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+    }
+    drop(tx);
+
+    let st = Instant::now();
+    for (i, ts, msg) in rx {
+        show(ts.duration_since(st).as_secs_f64(), i, devs.len(), &msg);
+    }
+    Ok(())
+}
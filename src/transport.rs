@@ -0,0 +1,738 @@
+//! Link abstraction: [`Endpoint`] describes a link, [`Transport`] opens it
+//! and provides reader / writer halves.
+//!
+//! ```no_run
+//! use msptest::parse_dev;
+//!
+//! let ep = parse_dev::parse_uri_dev("tcp://localhost:5760").unwrap();
+//! let mut t = ep.transport();
+//! t.open().unwrap();
+//! let rd = t.reader().unwrap();
+//! let wr = t.writer().unwrap();
+//! # drop((rd, wr));
+//! t.close();
+//! ```
+
+use crate::capture::{self, CaptureFile, Direction, Event};
+use crate::fault::{FaultOptions, FaultReader, FaultWriter};
+use crate::serial_options::{FlowControl, Parity, SerialOptions};
+use crate::{net, serial};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A link to a flight controller, as parsed from a device name or URI.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Endpoint {
+    Serial {
+        name: String,
+        baud: u32,
+        opts: SerialOptions,
+    },
+    Tcp {
+        host: String,
+        port: u16,
+    },
+    Udp {
+        host: String,
+        port: u16,
+        /// Fixed local port to bind to.
+        bind: Option<u16>,
+    },
+    /// Listen for a TCP connection on `host:port`.
+    TcpListen {
+        host: String,
+        port: u16,
+    },
+    /// Listen for UDP datagrams on `host:port`.
+    UdpListen {
+        host: String,
+        port: u16,
+    },
+    /// `inner` with faults injected in both directions.
+    Fault {
+        inner: Box<Endpoint>,
+        faults: FaultOptions,
+    },
+    /// Replay of a capture file, at `speed` times the recorded rate.
+    Replay {
+        path: String,
+        speed: f64,
+    },
+}
+
+impl Endpoint {
+    /// Creates the (unopened) transport for the endpoint.
+    pub fn transport(&self) -> Box<dyn Transport> {
+        match self {
+            Endpoint::Serial { name, baud, opts } => {
+                let mut t = SerialTransport::new(name, *baud);
+                t.set_options(*opts);
+                Box::new(t)
+            }
+            Endpoint::Tcp { host, port } => Box::new(TcpTransport::new(host, *port)),
+            Endpoint::Udp { host, port, bind } => {
+                let mut t = UdpTransport::new(host, *port);
+                t.set_bind_port(*bind);
+                Box::new(t)
+            }
+            Endpoint::TcpListen { host, port } => Box::new(TcpListenTransport::new(host, *port)),
+            Endpoint::UdpListen { host, port } => Box::new(UdpListenTransport::new(host, *port)),
+            Endpoint::Fault { inner, faults } => {
+                Box::new(FaultTransport::new(inner.transport(), faults.clone()))
+            }
+            Endpoint::Replay { path, speed } => {
+                let mut t = ReplayTransport::new(path);
+                t.set_speed(*speed);
+                Box::new(t)
+            }
+        }
+    }
+}
+
+// A host as written in a URI, with an IPv6 address in brackets.
+struct Host<'a>(&'a str);
+
+impl fmt::Display for Host<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.contains(':') {
+            write!(f, "[{}]", self.0)
+        } else {
+            f.write_str(self.0)
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Serial { name, baud, opts } if *opts == SerialOptions::default() => {
+                write!(f, "{}:{}", name, baud)
+            }
+            Endpoint::Serial { name, baud, opts } => {
+                // Line settings need the URI form.
+                let sep = if name.starts_with('/') { "" } else { "/" };
+                let parity = match opts.parity {
+                    Parity::None => "none",
+                    Parity::Odd => "odd",
+                    Parity::Even => "even",
+                };
+                let flow = match opts.flow {
+                    FlowControl::None => "none",
+                    FlowControl::RtsCts => "rtscts",
+                    FlowControl::XonXoff => "xonxoff",
+                };
+                write!(
+                    f,
+                    "serial://{}{}?baud={}&bits={}&parity={}&stop={}&flow={}",
+                    sep, name, baud, opts.data_bits, parity, opts.stop_bits, flow
+                )
+            }
+            Endpoint::Tcp { host, port } => write!(f, "tcp://{}:{}", Host(host), port),
+            Endpoint::Udp { host, port, bind } => {
+                write!(f, "udp://{}:{}", Host(host), port)?;
+                if let Some(b) = bind {
+                    write!(f, "?bind={}", b)?;
+                }
+                Ok(())
+            }
+            Endpoint::TcpListen { host, port } => write!(f, "tcp-listen://{}:{}", Host(host), port),
+            Endpoint::UdpListen { host, port } => write!(f, "udp-listen://{}:{}", Host(host), port),
+            Endpoint::Fault { inner, faults } => {
+                let inner = inner.to_string();
+                let sep = if inner.contains('?') { '&' } else { '?' };
+                write!(f, "fault+{}{}{}", inner, sep, faults)
+            }
+            Endpoint::Replay { path, speed } => {
+                write!(f, "replay://{}", path)?;
+                if *speed != 1.0 {
+                    write!(f, "?speed={}", speed)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A bidirectional byte link.
+///
+/// The reader half is typically moved to a [`crate::msp::reader`] thread;
+/// [`Transport::close`] should cause a blocked reader to return.
+pub trait Transport: Send {
+    fn open(&mut self) -> io::Result<()>;
+    /// Returns a reader for the open link.
+    fn reader(&self) -> io::Result<Box<dyn Read + Send>>;
+    /// Returns a writer for the open link.
+    fn writer(&self) -> io::Result<Box<dyn Write + Send>>;
+    fn close(&mut self);
+    /// Human readable description, e.g. for a status display.
+    fn description(&self) -> String;
+}
+
+fn not_open() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "transport not open")
+}
+
+/// Serial device transport.
+pub struct SerialTransport {
+    name: String,
+    baud: u32,
+    opts: SerialOptions,
+    sd: Option<serial::SerialDevice>,
+}
+
+impl SerialTransport {
+    pub fn new(name: &str, baud: u32) -> Self {
+        Self {
+            name: name.to_string(),
+            baud,
+            opts: SerialOptions::default(),
+            sd: None,
+        }
+    }
+
+    /// Sets the line settings used by subsequent opens (default 8N1).
+    pub fn set_options(&mut self, opts: SerialOptions) {
+        self.opts = opts;
+    }
+}
+
+impl Transport for SerialTransport {
+    fn open(&mut self) -> io::Result<()> {
+        let mut sd = serial::SerialDevice::new();
+        sd.open_with(&self.name, self.baud as isize, &self.opts)?;
+        sd.clear();
+        self.sd = Some(sd);
+        Ok(())
+    }
+
+    fn reader(&self) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(self.sd.clone().ok_or_else(not_open)?))
+    }
+
+    fn writer(&self) -> io::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(self.sd.clone().ok_or_else(not_open)?))
+    }
+
+    fn close(&mut self) {
+        if let Some(mut sd) = self.sd.take() {
+            sd.close();
+        }
+    }
+
+    fn description(&self) -> String {
+        if self.opts == SerialOptions::default() {
+            format!("{}:{}", self.name, self.baud)
+        } else {
+            format!("{}:{} {}", self.name, self.baud, self.opts)
+        }
+    }
+}
+
+/// TCP client transport.
+pub struct TcpTransport {
+    host: String,
+    port: u16,
+    conn: Option<TcpStream>,
+}
+
+impl TcpTransport {
+    pub fn new(host: &str, port: u16) -> Self {
+        Self {
+            host: host.to_string(),
+            port,
+            conn: None,
+        }
+    }
+}
+
+impl Transport for TcpTransport {
+    fn open(&mut self) -> io::Result<()> {
+        self.conn = Some(net::tcp_connect(&self.host, self.port)?);
+        Ok(())
+    }
+
+    fn reader(&self) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(
+            self.conn.as_ref().ok_or_else(not_open)?.try_clone()?,
+        ))
+    }
+
+    fn writer(&self) -> io::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(
+            self.conn.as_ref().ok_or_else(not_open)?.try_clone()?,
+        ))
+    }
+
+    fn close(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            _ = conn.shutdown(Shutdown::Both);
+        }
+    }
+
+    fn description(&self) -> String {
+        format!("tcp://{}:{}", self.host, self.port)
+    }
+}
+
+/// UDP client transport.
+pub struct UdpTransport {
+    host: String,
+    port: u16,
+    bind: Option<u16>,
+    sock: Option<net::UdpStream>,
+}
+
+impl UdpTransport {
+    pub fn new(host: &str, port: u16) -> Self {
+        Self {
+            host: host.to_string(),
+            port,
+            bind: None,
+            sock: None,
+        }
+    }
+
+    /// Sets a fixed local port to bind to (default any).
+    pub fn set_bind_port(&mut self, bind: Option<u16>) {
+        self.bind = bind;
+    }
+}
+
+impl Transport for UdpTransport {
+    fn open(&mut self) -> io::Result<()> {
+        self.sock = Some(net::udp_connect(&self.host, self.port, self.bind)?);
+        Ok(())
+    }
+
+    fn reader(&self) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(
+            self.sock.as_ref().ok_or_else(not_open)?.try_clone()?,
+        ))
+    }
+
+    fn writer(&self) -> io::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(
+            self.sock.as_ref().ok_or_else(not_open)?.try_clone()?,
+        ))
+    }
+
+    fn close(&mut self) {
+        if let Some(sock) = self.sock.take() {
+            sock.shutdown();
+        }
+    }
+
+    fn description(&self) -> String {
+        format!("udp://{}:{}", self.host, self.port)
+    }
+}
+
+impl Drop for TcpTransport {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl Drop for UdpTransport {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// TCP server transport. Each [`Transport::open`] waits for the next
+/// connection; the listening socket is kept open across `close`.
+pub struct TcpListenTransport {
+    host: String,
+    port: u16,
+    listener: Option<TcpListener>,
+    conn: Option<TcpStream>,
+}
+
+impl TcpListenTransport {
+    pub fn new(host: &str, port: u16) -> Self {
+        Self {
+            host: host.to_string(),
+            port,
+            listener: None,
+            conn: None,
+        }
+    }
+}
+
+impl Transport for TcpListenTransport {
+    fn open(&mut self) -> io::Result<()> {
+        if self.listener.is_none() {
+            self.listener = Some(TcpListener::bind((self.host.as_str(), self.port))?);
+        }
+        let (conn, _) = self.listener.as_ref().unwrap().accept()?;
+        _ = conn.set_nodelay(true);
+        self.conn = Some(conn);
+        Ok(())
+    }
+
+    fn reader(&self) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(
+            self.conn.as_ref().ok_or_else(not_open)?.try_clone()?,
+        ))
+    }
+
+    fn writer(&self) -> io::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(
+            self.conn.as_ref().ok_or_else(not_open)?.try_clone()?,
+        ))
+    }
+
+    fn close(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            _ = conn.shutdown(Shutdown::Both);
+        }
+    }
+
+    fn description(&self) -> String {
+        match self.conn.as_ref().and_then(|c| c.peer_addr().ok()) {
+            Some(peer) => format!("tcp-listen://{}:{} ({})", self.host, self.port, peer),
+            None => format!("tcp-listen://{}:{}", self.host, self.port),
+        }
+    }
+}
+
+impl Drop for TcpListenTransport {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// UDP server transport. Each [`Transport::open`] waits for a datagram from
+/// a peer; replies go to the sender of the latest datagram.
+pub struct UdpListenTransport {
+    host: String,
+    port: u16,
+    server: Option<net::UdpServer>,
+    sock: Option<net::UdpStream>,
+    // The accepted stream, which holds the first datagram, for the next
+    // reader.
+    accepted: Mutex<Option<net::UdpStream>>,
+}
+
+impl UdpListenTransport {
+    pub fn new(host: &str, port: u16) -> Self {
+        Self {
+            host: host.to_string(),
+            port,
+            server: None,
+            sock: None,
+            accepted: Mutex::new(None),
+        }
+    }
+}
+
+impl Transport for UdpListenTransport {
+    fn open(&mut self) -> io::Result<()> {
+        if self.server.is_none() {
+            self.server = Some(net::UdpServer::bind(&self.host, self.port)?);
+        }
+        let sock = self.server.as_ref().unwrap().accept()?;
+        self.sock = Some(sock.try_clone()?);
+        *self.accepted.lock().unwrap() = Some(sock);
+        Ok(())
+    }
+
+    fn reader(&self) -> io::Result<Box<dyn Read + Send>> {
+        if let Some(sock) = self.accepted.lock().unwrap().take() {
+            return Ok(Box::new(sock));
+        }
+        Ok(Box::new(
+            self.sock.as_ref().ok_or_else(not_open)?.try_clone()?,
+        ))
+    }
+
+    fn writer(&self) -> io::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(
+            self.sock.as_ref().ok_or_else(not_open)?.try_clone()?,
+        ))
+    }
+
+    fn close(&mut self) {
+        self.accepted.lock().unwrap().take();
+        if let Some(sock) = self.sock.take() {
+            sock.shutdown();
+        }
+    }
+
+    fn description(&self) -> String {
+        match self.sock.as_ref().and_then(|s| s.peer_addr().ok()) {
+            Some(peer) => format!("udp-listen://{}:{} ({})", self.host, self.port, peer),
+            None => format!("udp-listen://{}:{}", self.host, self.port),
+        }
+    }
+}
+
+impl Drop for UdpListenTransport {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Wraps another transport, injecting faults (see [`crate::fault`]) in
+/// both directions.
+pub struct FaultTransport {
+    inner: Box<dyn Transport>,
+    faults: FaultOptions,
+}
+
+impl FaultTransport {
+    pub fn new(inner: Box<dyn Transport>, faults: FaultOptions) -> Self {
+        Self { inner, faults }
+    }
+}
+
+impl Transport for FaultTransport {
+    fn open(&mut self) -> io::Result<()> {
+        self.inner.open()
+    }
+
+    fn reader(&self) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(FaultReader::new(
+            self.inner.reader()?,
+            &self.faults,
+        )))
+    }
+
+    fn writer(&self) -> io::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(FaultWriter::new(
+            self.inner.writer()?,
+            &self.faults,
+        )))
+    }
+
+    fn close(&mut self) {
+        self.inner.close();
+    }
+
+    fn description(&self) -> String {
+        format!("{} (faults {})", self.inner.description(), self.faults)
+    }
+}
+
+// Comparison of the writes to a replay with those recorded.
+#[derive(Default)]
+struct WriteCheck {
+    expected: VecDeque<Vec<u8>>,
+    written: VecDeque<Vec<u8>>,
+    compared: usize,
+    differ: usize,
+}
+
+impl WriteCheck {
+    fn compare(&mut self) {
+        while !self.expected.is_empty() && !self.written.is_empty() {
+            let e = self.expected.pop_front().unwrap();
+            let w = self.written.pop_front().unwrap();
+            self.compared += 1;
+            if e != w {
+                self.differ += 1;
+            }
+        }
+    }
+}
+
+/// Replays the data read in a capture file (see [`crate::capture`]) with
+/// the recorded timing, e.g. to reproduce a user's session or test decoder
+/// changes against real traffic without hardware.
+///
+/// Writes are accepted and compared with those recorded; the description
+/// includes the number that differ (over all replays since the transport
+/// was created). The reader returns end of file at the end of the capture.
+pub struct ReplayTransport {
+    path: String,
+    speed: f64,
+    file: Mutex<Option<CaptureFile<BufReader<File>>>>,
+    // Dropped on close, to wake a reader waiting for the next record.
+    cancel: Option<(Sender<()>, Receiver<()>)>,
+    check: Arc<Mutex<WriteCheck>>,
+}
+
+impl ReplayTransport {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            speed: 1.0,
+            file: Mutex::new(None),
+            cancel: None,
+            check: Default::default(),
+        }
+    }
+
+    /// Sets the replay speed relative to the recording (default 1); 0
+    /// replays without delays.
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed = speed;
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn open(&mut self) -> io::Result<()> {
+        *self.file.lock().unwrap() = Some(capture::open(Path::new(&self.path))?);
+        self.cancel = Some(crossbeam_channel::bounded(0));
+        // Counts are kept over successive replays.
+        let mut c = self.check.lock().unwrap();
+        c.expected.clear();
+        c.written.clear();
+        Ok(())
+    }
+
+    fn reader(&self) -> io::Result<Box<dyn Read + Send>> {
+        let file = self.file.lock().unwrap().take().ok_or_else(not_open)?;
+        let cancel = self.cancel.as_ref().ok_or_else(not_open)?.1.clone();
+        Ok(Box::new(ReplayReader {
+            file,
+            speed: self.speed,
+            start: Instant::now(),
+            t0: None,
+            cancel,
+            check: self.check.clone(),
+            buf: Vec::new(),
+            pos: 0,
+        }))
+    }
+
+    fn writer(&self) -> io::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(ReplayWriter {
+            check: self.check.clone(),
+        }))
+    }
+
+    fn close(&mut self) {
+        self.cancel = None;
+        *self.file.lock().unwrap() = None;
+    }
+
+    fn description(&self) -> String {
+        let mut s = format!("replay://{}", self.path);
+        if self.speed != 1.0 {
+            s += &format!(" x{}", self.speed);
+        }
+        let c = self.check.lock().unwrap();
+        if c.compared > 0 {
+            s += &format!(" ({} of {} writes differ)", c.differ, c.compared);
+        }
+        s
+    }
+}
+
+struct ReplayReader {
+    file: CaptureFile<BufReader<File>>,
+    speed: f64,
+    start: Instant,
+    // Time of the first record.
+    t0: Option<u64>,
+    cancel: Receiver<()>,
+    check: Arc<Mutex<WriteCheck>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl ReplayReader {
+    // Waits until `due` after the start; false if the transport was closed.
+    fn wait(&self, due: Duration) -> bool {
+        match due.checked_sub(self.start.elapsed()) {
+            Some(d) => matches!(self.cancel.recv_timeout(d), Err(RecvTimeoutError::Timeout)),
+            None => !matches!(self.cancel.try_recv(), Err(TryRecvError::Disconnected)),
+        }
+    }
+}
+
+impl Read for ReplayReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            let rec = match self.file.next() {
+                Some(r) => r?,
+                None => return Ok(0),
+            };
+            let t0 = *self.t0.get_or_insert(rec.time_us);
+            match rec.event {
+                Event::Data {
+                    dir: Direction::FromFc,
+                    data,
+                } => {
+                    let due = if self.speed == 0.0 {
+                        Duration::ZERO
+                    } else {
+                        let t = rec.time_us.saturating_sub(t0) as f64 / 1e6;
+                        Duration::try_from_secs_f64(t / self.speed).map_err(|_| {
+                            io::Error::new(io::ErrorKind::InvalidInput, "replay delay out of range")
+                        })?
+                    };
+                    if !self.wait(due) {
+                        return Ok(0);
+                    }
+                    self.buf = data;
+                    self.pos = 0;
+                }
+                Event::Data {
+                    dir: Direction::ToFc,
+                    data,
+                } => {
+                    let mut c = self.check.lock().unwrap();
+                    c.expected.push_back(data);
+                    c.compare();
+                }
+                Event::Session { .. } => (),
+            }
+        }
+        let n = buf.len().min(self.buf.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+struct ReplayWriter {
+    check: Arc<Mutex<WriteCheck>>,
+}
+
+impl Write for ReplayWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut c = self.check.lock().unwrap();
+        c.written.push_back(buf.to_vec());
+        c.compare();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+
+    #[test]
+    fn udp_listen_keeps_first_datagram() {
+        let mut t = UdpListenTransport::new("127.0.0.1", 0);
+        t.server = Some(net::UdpServer::bind("127.0.0.1", 0).unwrap());
+        let addr = t.server.as_ref().unwrap().local_addr().unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.send_to(b"$M<\x00\x64\x64", addr).unwrap();
+        t.open().unwrap();
+        let mut rd = t.reader().unwrap();
+        let mut buf = [0; 16];
+        let n = rd.read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"$M<\x00\x64\x64");
+
+        t.writer().unwrap().write_all(b"reply").unwrap();
+        let (n, _) = peer.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"reply");
+        t.close();
+    }
+}